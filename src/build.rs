@@ -1,10 +1,65 @@
-use std::{cmp::min, collections::HashMap, fs::File, io::BufRead, path::Path, sync::{Arc, Mutex}};
+use std::{cmp::{min, Ordering}, collections::{BinaryHeap, HashMap, HashSet}, io::BufRead, mem, ops::Range, path::{Path, PathBuf}, sync::{mpsc::{self, SyncSender}, Arc, Mutex}, thread};
 
-use kmerrs::{consecutive::kmer::KmerIter, minimizer::context_free::Minimizer, syncmer::closed_syncmer::ClosedSyncmer};
+use kmerrs::consecutive::kmer::Kmer;
 use bioreader::{fasta_byte_reader::{self, FastaByteReader}, fasta_reader::{self, FastaReader}, fastq_byte_reader, fastq_reader, sequence::fasta_record::OwnedFastaRecord};
 use savefile::save;
 
-use crate::{flexmap::{Flexmap, FlexmapHash, KeysHashSmall}, keys::{self, FMKeys, FMKeysHash}, values::VData, VD};
+use crate::{catalog::{ReferenceCatalog, ReferenceEntry}, error::FlexmapError, input::{open_reader, BuildInput}, layout::BlockLayout, seeds::{Seed, SeedScheme, SeedSelection, SeedSelector}, spill::{Runs, SeedRecord, SpillOptions, SpillWriter}, flexmap::{FlexIndex, FlexOptions, Flexmap, FlexmapHash}, keys::{BuildReport, FMKeys, FMKeysHash, RepeatPolicy}, values::{FMValues, FillCursors, Strand, VCell, ValueLayout}};
+
+/// Name of a reference is the first word of the FASTA header.
+fn reference_name(record: &OwnedFastaRecord) -> String {
+    reference_entry(record).name
+}
+
+/// Catalog entry of a record: name is the first word of the FASTA header, description the rest.
+fn reference_entry(record: &OwnedFastaRecord) -> ReferenceEntry {
+    let head = record.head();
+    let head = if head.first() == Some(&b'>') { &head[1..] } else { head };
+    let head = String::from_utf8_lossy(head);
+    let (name, description) = head.trim_end().split_once(' ').unwrap_or((head.trim_end(), ""));
+    ReferenceEntry {
+        name: name.to_string(),
+        length: record.seq().len() as u64,
+        description: description.trim().to_string(),
+        group: None,
+        source: None,
+    }
+}
+
+
+/// Picks the orientation whose core is canonical and returns (core, k-mer, strand).
+/// The k-mer (and therefore its flanks) is always taken in the orientation of the
+/// canonical core. For palindromic cores the smaller k-mer decides.
+pub fn canonical<const K: usize, const C: usize>(kmer_fwd: Kmer<K>, kmer_rev: Kmer<K>) -> (Kmer<C>, Kmer<K>, Strand) {
+    let cmer_fwd = kmer_fwd.middle::<C>();
+    let cmer_rev = kmer_rev.middle::<C>();
+    let strand = match cmer_fwd.cmp(&cmer_rev) {
+        Ordering::Less => Strand::Forward,
+        Ordering::Greater => Strand::Reverse,
+        Ordering::Equal => if kmer_fwd <= kmer_rev { Strand::Forward } else { Strand::Reverse },
+    };
+    match strand {
+        Strand::Forward => (cmer_fwd, kmer_fwd, strand),
+        Strand::Reverse => (cmer_rev, kmer_rev, strand),
+    }
+}
+
+/// Validates a record read from the source file with index source and adds it to the catalog, returns its reference id.
+fn add_reference(path: &Path, source: usize, catalog: &mut ReferenceCatalog, record: &OwnedFastaRecord) -> Result<usize, FlexmapError> {
+    let entry = ReferenceEntry { source: Some(source), ..reference_entry(record) };
+    if !record.valid_extended() {
+        return Err(FlexmapError::InvalidSequence { path: path.to_path_buf(), reference: entry.name });
+    }
+    catalog.insert(entry)
+        .map_err(|entry| FlexmapError::DuplicateReference { path: path.to_path_buf(), reference: entry.name })
+}
+
+fn check_reference(path: &Path, record: &OwnedFastaRecord) -> Result<usize, FlexmapError> {
+    if !record.valid_extended() {
+        return Err(FlexmapError::InvalidSequence { path: path.to_path_buf(), reference: reference_name(record) });
+    }
+    Ok(0)
+}
 
 fn find_min<'a, I>(vals: I) -> Option<&'a u32>
 where
@@ -13,6 +68,275 @@ where
     vals.min()
 }
 
+/// Options of default_build and hash_build.
+#[derive(Clone, Copy, Debug)]
+pub struct BuildOptions {
+    /// Number of worker threads, 0 uses all available cores.
+    pub threads: usize,
+    /// Keys with more occurrences are not indexed.
+    pub max_range_size: usize,
+    /// What happens to keys with more occurrences.
+    pub repeat_policy: RepeatPolicy,
+    pub layout: ValueLayout,
+    /// Which k-mers of the references are indexed.
+    pub seeds: SeedScheme,
+}
+
+impl FlexOptions for BuildOptions {}
+
+impl BuildOptions {
+    pub fn new(max_range_size: usize) -> Self {
+        BuildOptions { threads: 0, max_range_size, repeat_policy: RepeatPolicy::Drop, layout: ValueLayout::default(), seeds: SeedScheme::default() }
+    }
+
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            threads => threads,
+        }
+    }
+}
+
+/// Part of a reference sequence handed to a worker. Every k-mer is owned by
+/// exactly one chunk, the k-mers around the owned ones that decide whether
+/// those are seeds (SeedSelection::context) are included as well.
+struct Chunk {
+    reference_id: usize,
+    /// Position of seq in the reference.
+    offset: usize,
+    seq: Vec<u8>,
+    /// Positions in seq of the k-mers owned by this chunk.
+    owned: Range<usize>,
+}
+
+impl Chunk {
+    /// Seeds of the k-mers owned by this chunk, with positions in seq.
+    fn seeds<'a, const K: usize, const C: usize>(&'a self, selector: &'a mut impl SeedSelection<K, C>) -> impl Iterator<Item = Seed<K, C>> + 'a {
+        selector.seeds(&self.seq).filter(|seed| self.owned.contains(&seed.pos))
+    }
+}
+
+/// Number of k-mers owned by a chunk and number of bases per batch sent to the workers.
+const CHUNK_SIZE: usize = 1 << 20;
+const BATCH_SIZE: usize = 1 << 24;
+
+/// Reads paths one after the other on the calling thread and processes their
+/// sequences on `threads` workers, in chunks with `context` k-mers on either
+/// side. `reference` is called with the index of the file for every record in
+/// input order and returns its reference id, so ids do not depend on the number
+/// of threads. Each worker creates its state with `init` and calls `work` on the
+/// chunks it receives. Returns the states of all workers.
+fn process_par<const K: usize, St, R, I, W>(paths: &[PathBuf], threads: usize, context: usize, mut reference: R, init: I, work: W) -> Result<Vec<St>, FlexmapError>
+where
+    St: Send,
+    R: FnMut(usize, &OwnedFastaRecord) -> Result<usize, FlexmapError>,
+    I: Fn() -> Result<St, FlexmapError> + Sync,
+    W: Fn(&mut St, &Chunk) -> Result<(), FlexmapError> + Sync,
+{
+    let (sender, receiver) = mpsc::sync_channel::<Vec<Chunk>>(threads * 2);
+    // Workers own the receiver, if all of them fail the reader stops at the next send.
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| {
+            let receiver = Arc::clone(&receiver);
+            let (init, work) = (&init, &work);
+            scope.spawn(move || {
+                let mut state = init()?;
+                loop {
+                    let batch = match receiver.lock().expect("Receiver lock").recv() {
+                        Ok(batch) => batch,
+                        Err(_) => break,
+                    };
+                    for chunk in &batch {
+                        work(&mut state, chunk)?;
+                    }
+                }
+                Ok(state)
+            })
+        }).collect();
+        drop(receiver);
+
+        let read = read_chunks::<K, R>(paths, context, &mut reference, &sender);
+        drop(sender);
+
+        let states = workers.into_iter()
+            .map(|worker| worker.join().expect("Build worker panicked"))
+            .collect::<Result<Vec<St>, FlexmapError>>()?;
+        read.map(|_| states)
+    })
+}
+
+fn read_chunks<const K: usize, R>(paths: &[PathBuf], context: usize, reference: &mut R, sender: &SyncSender<Vec<Chunk>>) -> Result<(), FlexmapError>
+where
+    R: FnMut(usize, &OwnedFastaRecord) -> Result<usize, FlexmapError>,
+{
+    let mut record = OwnedFastaRecord::new();
+    let buffer_size = usize::pow(2, 24);
+
+    let mut batch = Vec::new();
+    let mut batch_size = 0;
+    for (file, path) in paths.iter().enumerate() {
+        let mut byte_reader = Arc::new(Mutex::new(FastaByteReader::new(open_reader(path)?, buffer_size).map_err(|e| FlexmapError::io(path, e))?));
+        let mut fasta_reader = FastaReader::with_capacity(buffer_size);
+
+        while let Some(()) = fasta_reader
+            .load_batch_par(&mut byte_reader)
+            .map_err(|e| FlexmapError::parse(path, e))?
+        {
+            while let Some(_) = fasta_reader.next(&mut record) {
+                let reference_id = reference(file, &record)?;
+                let seq = record.seq();
+
+                let mut first = 0;
+                while first + K <= seq.len() {
+                    let last = min(first + CHUNK_SIZE, seq.len() + 1 - K);
+                    let begin = first.saturating_sub(context);
+                    let end = min(last + context + K - 1, seq.len());
+                    batch.push(Chunk { reference_id, offset: begin, seq: seq[begin..end].to_vec(), owned: first - begin..last - begin });
+                    batch_size += end - begin;
+                    first += CHUNK_SIZE;
+                }
+
+                if batch_size >= BATCH_SIZE {
+                    // Only fails if all workers stopped, their error is reported by process_par.
+                    if sender.send(mem::take(&mut batch)).is_err() { return Ok(()) };
+                    batch_size = 0;
+                }
+            }
+        }
+    }
+    if !batch.is_empty() {
+        let _ = sender.send(batch);
+    }
+    Ok(())
+}
+
+fn occurrence(layout: ValueLayout, reference_id: usize, pos: usize, strand: Strand) -> Result<VCell, FlexmapError> {
+    let mut cell = VCell(layout.pack(reference_id as u64, pos as u64)?);
+    cell.set_strand(strand);
+    Ok(cell)
+}
+
+/// State of a worker in the map pass.
+struct MapState<Sel> {
+    selector: Sel,
+    total_kmers: u64,
+    total_minimizers: u64,
+    /// Occurrences of subsampled keys, placed by finish_map.
+    capped: Reservoir,
+}
+
+impl<Sel> MapState<Sel> {
+    fn new(selector: Sel, policy: RepeatPolicy) -> Self {
+        MapState { selector, total_kmers: 0, total_minimizers: 0, capped: Reservoir::new(policy) }
+    }
+}
+
+/// Keys subsampled by the repeat policy.
+fn capped_keys(report: &BuildReport) -> HashSet<u64> {
+    report.subsampled.iter().map(|&(key, _)| key).collect()
+}
+
+/// Samples at most cap occurrences per key while the occurrences stream by,
+/// as (cell, flanks). Keeps the occurrences with the smallest hashes, so the
+/// sample does not depend on the order the occurrences arrive in and the
+/// reservoirs of several workers merge into the sample of all occurrences.
+#[derive(Default)]
+struct Reservoir {
+    cap: usize,
+    keys: HashMap<u64, BinaryHeap<(u64, u64, u32)>>,
+}
+
+impl Reservoir {
+    fn new(policy: RepeatPolicy) -> Self {
+        let cap = match policy {
+            RepeatPolicy::Drop => 0,
+            RepeatPolicy::Subsample { cap } => cap,
+        };
+        Reservoir { cap, keys: HashMap::new() }
+    }
+
+    fn insert(&mut self, key: u64, cell: VCell, flanks: u32) {
+        let priority = FMKeysHash::hash(cell.0 ^ FMKeysHash::hash(key));
+        self.push(key, (priority, cell.0, flanks));
+    }
+
+    fn push(&mut self, key: u64, entry: (u64, u64, u32)) {
+        let sample = self.keys.entry(key).or_default();
+        if sample.len() < self.cap {
+            sample.push(entry);
+        } else if let Some(mut largest) = sample.peek_mut() {
+            if entry < *largest {
+                *largest = entry;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Reservoir) {
+        for (key, sample) in other.keys {
+            for entry in sample {
+                self.push(key, entry);
+            }
+        }
+    }
+
+    /// Removes the sample of key and returns n of its occurrences.
+    fn take(&mut self, key: u64, n: usize) -> impl Iterator<Item = (VCell, u32)> {
+        let sample = self.keys.remove(&key).unwrap_or_default();
+        sample.into_sorted_vec().into_iter().take(n).map(|(_, cell, flanks)| (VCell(cell), flanks))
+    }
+}
+
+/// Fills the blocks of subsampled keys with the sampled occurrences and
+/// orders every block by reference and position, which is the order a
+/// sequential build inserts occurrences in. part_ranges(part, threads) are
+/// the value ranges of the part-th of threads disjoint parts of the keys.
+fn finish_map<Sel, R, P, I, const F: usize, const HEADER_THRESHOLD: usize>(
+    values: &mut FMValues<F, HEADER_THRESHOLD>,
+    states: Vec<MapState<Sel>>,
+    range_of: R,
+    part_ranges: P,
+    threads: usize,
+) -> Result<(), FlexmapError>
+where
+    R: Fn(u64) -> Option<(usize, usize)>,
+    P: Fn(usize, usize) -> I + Sync,
+    I: Iterator<Item = (usize, usize)>,
+{
+    let total_kmers: u64 = states.iter().map(|state| state.total_kmers).sum();
+    let total_minimizers: u64 = states.iter().map(|state| state.total_minimizers).sum();
+    eprintln!("Minimizer compression rate: {} ({}/{})", total_minimizers as f64/total_kmers as f64, total_minimizers, total_kmers);
+
+    let mut reservoirs = states.into_iter().map(|state| state.capped);
+    let mut capped = reservoirs.next().unwrap_or_default();
+    for reservoir in reservoirs {
+        capped.merge(reservoir);
+    }
+    let keys: Vec<u64> = capped.keys.keys().copied().collect();
+    for key in keys {
+        let range = range_of(key).expect("Subsampled keys have a range");
+        let mut vblock = values.get_range_mut(range);
+        for (slot, (cell, flanks)) in capped.take(key, vblock.positions.len()).enumerate() {
+            vblock.insert_cell(slot, cell, flanks)?;
+        }
+    }
+
+    // Every key is in exactly one part, so the threads sort disjoint blocks.
+    let shared = values.shared();
+    thread::scope(|scope| {
+        for part in 0..threads {
+            let (shared, part_ranges) = (&shared, &part_ranges);
+            scope.spawn(move || {
+                for range in part_ranges(part, threads) {
+                    unsafe { shared.range_mut(range) }.sort();
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
 pub fn default_build<
     const K: usize,
     const C: usize,
@@ -21,21 +345,15 @@ pub fn default_build<
     const L: usize,
     const CELLS_PER_BODY: u64,
     const HEADER_THRESHOLD: usize
->(path: impl AsRef<Path>, max_range_size: usize) -> 
-        Result<(Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>, HashMap<String, usize>, Vec<String>), std::io::Error> {
-    eprintln!("Build keys");
-    let keys = match default_build_keys::<K, C, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>(&path, max_range_size) {
-        Ok(keys) => keys,
-        Err(_) => panic!("Keys could not be built."),
-    };
+>(input: &BuildInput, options: &BuildOptions) -> 
+        Result<(Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>, BuildReport), FlexmapError> {
+    let paths = input.paths()?;
+    eprintln!("Build keys from {} files", paths.len());
+    let (keys, report) = default_build_keys::<K, C, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>(&paths, options)?;
 
     eprintln!("Build map");
-    let (map, reference2id, id2reference) = match default_build_map::<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>(path, keys) {
-        Ok(map) => map,
-        Err(_) => panic!("Map could not be built."),
-    };
-
-    Ok((map, reference2id, id2reference))
+    let flexmap = default_build_map::<K, C, F, S, L, CELLS_PER_BODY, HEADER_THRESHOLD>(&paths, keys, &report, options)?;
+    Ok((flexmap, report))
 }
 
 
@@ -46,82 +364,126 @@ pub fn hash_build<
     const S: usize,
     const L: usize,
     const HEADER_THRESHOLD: usize
->(path: impl AsRef<Path>, max_range_size: usize) -> 
-        Result<(FlexmapHash<C, F, HEADER_THRESHOLD>, HashMap<String, usize>, Vec<String>), std::io::Error> {
-    eprintln!("Build keys");
-    let keys = match hash_build_keys::<K, C, S, L, HEADER_THRESHOLD>(&path, max_range_size) {
-        Ok(keys) => keys,
-        Err(_) => panic!("Keys could not be built."),
-    };
+>(input: &BuildInput, options: &BuildOptions) -> 
+        Result<(FlexmapHash<C, F, HEADER_THRESHOLD>, BuildReport), FlexmapError> {
+    let paths = input.paths()?;
+    eprintln!("Build keys from {} files", paths.len());
+    let (keys, report) = hash_build_keys::<K, C, S, L, HEADER_THRESHOLD>(&paths, options)?;
 
     eprintln!("Build map");
-    let (map, reference2id, id2reference) = match hash_build_map::<K, C, F, S, L, HEADER_THRESHOLD>(path, keys) {
-        Ok(map) => map,
-        Err(_) => panic!("Map could not be built."),
-    };
-
-    Ok((map, reference2id, id2reference))
+    let flexmap = hash_build_map::<K, C, F, S, L, HEADER_THRESHOLD>(&paths, keys, &report, options)?;
+    Ok((flexmap, report))
 }
 
-fn default_build_keys<
+/// Builds the same index as default_build but reads the input only once. The
+/// seeds are collected in sorted runs in spill.dir while the keys are counted,
+/// the values are then filled from the merged runs.
+pub fn spill_build<
     const K: usize,
     const C: usize,
+    const F: usize,
     const S: usize,
     const L: usize,
     const CELLS_PER_BODY: u64,
-    const HEADER_THRESHOLD: usize,
->(path: impl AsRef<Path>, max_range_size: usize) -> Result<FMKeys<C, CELLS_PER_BODY>, std::io::Error> {
-    let mut keys = FMKeys::<C, CELLS_PER_BODY>::new();
-
-    let file: File = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.as_ref().display(), why),
-        Ok(file) => file,
-    };
+    const HEADER_THRESHOLD: usize
+>(input: &BuildInput, options: &BuildOptions, spill: &SpillOptions) -> 
+        Result<(Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>, BuildReport), FlexmapError> {
+    let paths = input.paths()?;
+    let threads = options.threads();
+    let layout = options.layout;
 
-    let mut record = OwnedFastaRecord::new();
-    
-    let buffer_size = usize::pow(2, 24);
-    let mut byte_reader = Arc::new(Mutex::new(FastaByteReader::new(file, buffer_size)?));
-    let mut fasta_reader = FastaReader::with_capacity(buffer_size);
-    let mut cs = ClosedSyncmer::<C,S,L>::new();
+    let mut keys = FMKeys::<C, CELLS_PER_BODY>::new();
+    let mut catalog = ReferenceCatalog::new();
+    for path in &paths {
+        catalog.add_source(path);
+    }
 
-    let mut kmer_count = 0;
-    eprintln!("read data");
-    while let Some(()) = fasta_reader
-        .load_batch_par(&mut byte_reader)
-        .expect("Batch is invalid")
-    {
-        while let Some(_) = fasta_reader.next(&mut record) {
-            if !record.valid_extended() {
-                panic!("Record is not valid {:?}", record.to_string())
+    eprintln!("Collect seeds from {} files", paths.len());
+    let cells = keys.atomic_cells();
+    let states = process_par::<K, _, _, _, _>(&paths, threads, SeedSelector::<K,C,S,L>::new(options.seeds)?.context(),
+        |file, record| add_reference(&paths[file], file, &mut catalog, record),
+        || Ok((SeedSelector::<K,C,S,L>::new(options.seeds)?, SpillWriter::new(&spill.dir, spill.memory / threads))),
+        |(selector, writer), chunk| {
+            for seed in chunk.seeds(selector) {
+                FMKeys::<C, CELLS_PER_BODY>::increment_atomic(cells, seed.core.0);
+
+                let cell = occurrence(layout, chunk.reference_id, chunk.offset + seed.pos, seed.strand)?;
+                writer.push(SeedRecord { cmer: seed.core.0, cell: cell.0, flanks: seed.kmer.flanks::<F>().0 as u32 })?;
             }
+            Ok(())
+        })?;
 
-            let mut iter = KmerIter::<K, true>::new(record.seq());
+    let mut runs = Runs::default();
+    for (_, writer) in states {
+        runs.extend(writer.finish()?);
+    }
 
-            for (_, kmer_fwd, kmer_rev) in iter {
-                let cmer_fwd = kmer_fwd.middle::<C>();
-                let cmer_rev = kmer_rev.middle::<C>();
-                let kmer = if cmer_fwd < cmer_rev { kmer_fwd } else { kmer_rev };
-                let cmer = min(cmer_fwd, cmer_rev);
+    eprintln!("Keys build {} {}", HEADER_THRESHOLD, options.max_range_size);
+    let report = keys.build::<HEADER_THRESHOLD>(options.max_range_size, options.repeat_policy);
+    let capped = capped_keys(&report);
+
+    let mut flexmap = Flexmap::<C,F,CELLS_PER_BODY,HEADER_THRESHOLD>::with_layout(keys, layout);
+    flexmap.seeding = SeedSelector::<K,C,S,L>::new(options.seeds)?.params();
+    flexmap.catalog = catalog;
+
+    // Seeds arrive grouped by key and in the order of the values, so each block
+    // is filled front to back. Seeds of subsampled keys are sampled first.
+    eprintln!("Fill values from {} runs", runs.paths().len());
+    let mut sample = Reservoir::new(options.repeat_policy);
+    let mut slot = 0;
+    let mut records = runs.merge()?.peekable();
+    while let Some(record) = records.next() {
+        let record = record?;
+        let Some(range) = flexmap.keys.vrange(record.cmer) else { continue };
+        let last = !matches!(records.peek(), Some(Ok(next)) if next.cmer == record.cmer);
+        let mut vblock = flexmap.values.get_range_mut(range);
+        if capped.contains(&record.cmer) {
+            sample.insert(record.cmer, VCell(record.cell), record.flanks);
+            if last {
+                for (slot, (cell, flanks)) in sample.take(record.cmer, vblock.positions.len()).enumerate() {
+                    vblock.insert_cell(slot, cell, flanks)?;
+                }
+                vblock.sort();
+            }
+        } else {
+            vblock.insert_cell(slot, VCell(record.cell), record.flanks)?;
+            slot = if last { 0 } else { slot + 1 };
+        }
+    }
 
-                if !cs.is_minimizer(cmer.0) { continue };
+    flexmap.verify()?;
 
-                // if cmer.is_own_rc() { continue };
+    eprintln!("Number of ids: {}", flexmap.catalog.len());
 
-                // println!("{:?}", cmer.to_string());
+    Ok((flexmap, report))
+}
 
-                // let table_size = keys::FMKeys::<C, CELLS_PER_BODY>::table_size();
-                // let index = keys::FMKeys::<C, CELLS_PER_BODY>::kmer_to_ctrl_block_index(cmer.0);
+fn default_build_keys<
+    const K: usize,
+    const C: usize,
+    const S: usize,
+    const L: usize,
+    const CELLS_PER_BODY: u64,
+    const HEADER_THRESHOLD: usize,
+>(paths: &[PathBuf], options: &BuildOptions) -> Result<(FMKeys<C, CELLS_PER_BODY>, BuildReport), FlexmapError> {
+    let mut keys = FMKeys::<C, CELLS_PER_BODY>::new();
 
-                keys.get_kmer_cell_mut_ref(cmer.0).increment();
+    eprintln!("read data");
+    let cells = keys.atomic_cells();
+    process_par::<K, _, _, _, _>(paths, options.threads(), SeedSelector::<K,C,S,L>::new(options.seeds)?.context(),
+        |file, record| check_reference(&paths[file], record),
+        || SeedSelector::<K,C,S,L>::new(options.seeds),
+        |selector, chunk| {
+            for seed in chunk.seeds(selector) {
+                FMKeys::<C, CELLS_PER_BODY>::increment_atomic(cells, seed.core.0);
             }
-        }
-    }
+            Ok(())
+        })?;
 
-    eprintln!("Keys build {} {}", HEADER_THRESHOLD, max_range_size);
-    keys.build::<HEADER_THRESHOLD>(max_range_size);
+    eprintln!("Keys build {} {}", HEADER_THRESHOLD, options.max_range_size);
+    let report = keys.build::<HEADER_THRESHOLD>(options.max_range_size, options.repeat_policy);
 
-    Ok(keys)
+    Ok((keys, report))
 }
 
 
@@ -132,62 +494,45 @@ fn hash_build_keys<
     const S: usize,
     const L: usize,
     const HEADER_THRESHOLD: usize,
->(path: impl AsRef<Path>, max_range_size: usize) -> Result<FMKeysHash, std::io::Error> {
-    let mut keys_counter = HashMap::<u32, u32>::new();
-
-    let file: File = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.as_ref().display(), why),
-        Ok(file) => file,
-    };
+>(paths: &[PathBuf], options: &BuildOptions) -> Result<(FMKeysHash, BuildReport), FlexmapError> {
 
-    let mut record = OwnedFastaRecord::new();
-    
-    let buffer_size = usize::pow(2, 24);
-    let mut byte_reader = Arc::new(Mutex::new(FastaByteReader::new(file, buffer_size)?));
-    let mut fasta_reader = FastaReader::with_capacity(buffer_size);
-    let mut cs = ClosedSyncmer::<C,S,L>::new();
-
-    let mut kmer_count = 0;
     println!("read data");
-    while let Some(()) = fasta_reader
-        .load_batch_par(&mut byte_reader)
-        .expect("Batch is invalid")
-    {
-        while let Some(_) = fasta_reader.next(&mut record) {
-            if !record.valid_extended() {
-                panic!("Record is not valid {:?}", record.to_string())
+    let counters = process_par::<K, _, _, _, _>(paths, options.threads(), SeedSelector::<K,C,S,L>::new(options.seeds)?.context(),
+        |file, record| check_reference(&paths[file], record),
+        || Ok((SeedSelector::<K,C,S,L>::new(options.seeds)?, HashMap::<u64, u32>::new())),
+        |(selector, keys_counter), chunk| {
+            for seed in chunk.seeds(selector) {
+                *keys_counter.entry(seed.core.0).or_insert(0) += 1;
             }
+            Ok(())
+        })?;
 
-            let mut iter = KmerIter::<K, true>::new(record.seq());
-
-            for (_, kmer_fwd, kmer_rev) in iter {
-                let cmer_fwd = kmer_fwd.middle::<C>();
-                let cmer_rev = kmer_rev.middle::<C>();
-                let kmer = if cmer_fwd < cmer_rev { kmer_fwd } else { kmer_rev };
-                let cmer = min(cmer_fwd, cmer_rev);
-
-                if !cs.is_minimizer(cmer.0) { continue };
-                // if cmer.is_own_rc() { continue };
-
-                // keys_counter.get
-                let values = keys_counter.entry(cmer.0 as u32).or_insert(0);
-                *values += 1;
-            }
+    let mut keys_counter = HashMap::<u64, u32>::new();
+    for (_, counter) in counters {
+        for (cmer, count) in counter {
+            *keys_counter.entry(cmer).or_insert(0) += count;
         }
     }
+    // Ranges are assigned in key order so the layout does not depend on the threads.
+    let mut keys_counter: Vec<(u64, u32)> = keys_counter.into_iter().collect();
+    keys_counter.sort_unstable();
 
-    let mut keys: FMKeysHash = FMKeysHash::with_capacity(keys_counter.len() * 2);
+    let mut keys: FMKeysHash = FMKeysHash::with_expected_keys(keys_counter.len());
+    let mut report = BuildReport::default();
     
     let mut running_v = 0;
     eprintln!("Insert ranges {}", keys_counter.len());
-    let mut inserted = 0;
-    keys_counter.iter().for_each(|(&cmer, &size)| {
-        keys.insert(cmer as u32, running_v as u64, size as u32);
-        running_v += size;
-    });
+    for &(cmer, count) in &keys_counter {
+        let count = options.repeat_policy.apply(cmer, count as u64, options.max_range_size, FMKeysHash::MAX_KEY_VALUESSIZE, &mut report);
+        if count == 0 { continue };
+        // Reserve the flank header like FMKeys::build, otherwise the block is full before all occurrences are in.
+        let size = BlockLayout::for_occurrences::<HEADER_THRESHOLD>(count as usize).size() as u32;
+        keys.insert(cmer, running_v as u64, size)?;
+        running_v += size as u64;
+    }
     eprintln!("{}", running_v);
 
-    Ok(keys)
+    Ok((keys, report))
 }
 
 
@@ -200,99 +545,58 @@ fn default_build_map<
     const L: usize,
     const CELLS_PER_BODY: u64,
     const HEADER_THRESHOLD: usize,
->(path: impl AsRef<Path>, keys: FMKeys<C, CELLS_PER_BODY>) -> 
-        Result<(Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>, HashMap<String, usize>, Vec<String>), std::io::Error> {
-
-    let mut cs = ClosedSyncmer::<K,S,L>::new();
-    let file: File = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.as_ref().display(), why),
-        Ok(file) => file,
-    };
-
-    let mut flexmap = Flexmap::<C,F,CELLS_PER_BODY,HEADER_THRESHOLD>::new(keys);
-
-    let mut record = OwnedFastaRecord::new();
-    
-    let buffer_size = usize::pow(2, 24);
-    let mut byte_reader = Arc::new(Mutex::new(FastaByteReader::new(file, buffer_size)?));
-    let mut fasta_reader = FastaReader::with_capacity(buffer_size);
-    
-    
-    let kmer_count = 0u64;
-    let mut own_rc_count = 0u64;
-
-    let mut reference_running_id = 1usize;
-    let mut reference2id: HashMap<String, usize> = HashMap::<String, usize>::new();
-    let mut id2reference: Vec<String> = Vec::<String>::new();
-    id2reference.push("dummy".into());
-
-    let mut total_kmers = 0u64;
-    let mut total_minimizers = 0u64;
-    
-    while let Some(()) = fasta_reader
-        .load_batch_par(&mut byte_reader)
-        .expect("Batch is invalid")
-    {
-
-        while let Some(_) = fasta_reader.next(&mut record) {
-            if !record.valid_extended() {
-                panic!("Record is not valid {:?}", record.to_string())
-            }
-            let iter = KmerIter::<K, true>::new(record.seq());
-
-            let header = String::from_utf8_lossy(&record.head()[1..]).split(' ').next().unwrap().to_string();
-
-            let reference_id = match reference2id
-                    .try_insert(header.clone(), reference_running_id) {
-                Ok(reference_id) => *reference_id,
-                Err(_) => panic!("Header {:?} has been seen before!", record.head()),
-            };
-            id2reference.push(header.clone());
-            assert_eq!(id2reference[reference_id], header);
-
-            reference_running_id += 1;
-
-
-            // println!("{:?} -> {}", String::from_utf8_lossy(record.head()), reference_id);
-
-            for (pos, kmer_fwd, kmer_rev) in iter {
-                let cmer_fwd = kmer_fwd.middle::<C>();
-                let cmer_rev = kmer_rev.middle::<C>();
-                let kmer = if cmer_fwd < cmer_rev { kmer_fwd } else { kmer_rev };
-                let cmer = min(cmer_fwd, cmer_rev);
-
-                total_kmers += 1;
-
-                if !cs.is_minimizer(cmer.0) { continue };
-
-                total_minimizers += 1;
-
-                // if cmer.is_own_rc() { 
-                //     own_rc_count += 1;
-                //     continue 
-                // };
-
-                let flanks = kmer.flanks::<F>();
-        
-                match flexmap.keys.vrange(cmer.0) {
-                    Some(range) => {
-                        let mut vblock = flexmap.values.get_range_mut(range);
-                        vblock.insert(VD::set(reference_id as u64, pos as u64), flanks.0 as u32);
-                    },
-                    None => {},
-                };
+>(paths: &[PathBuf], keys: FMKeys<C, CELLS_PER_BODY>, report: &BuildReport, options: &BuildOptions) -> 
+        Result<Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>, FlexmapError> {
+    let layout = options.layout;
+    let capped = capped_keys(report);
+
+    let mut flexmap = Flexmap::<C,F,CELLS_PER_BODY,HEADER_THRESHOLD>::with_layout(keys, layout);
+    flexmap.seeding = SeedSelector::<K,C,S,L>::new(options.seeds)?.params();
+    for path in paths {
+        flexmap.catalog.add_source(path);
+    }
 
-                // if reference_id == 1 && pos == 578574;
+    let slots = flexmap.keys.key_slots()?;
+    let cursors = FillCursors::new(slots.len());
+    let keys = &flexmap.keys;
+    let catalog = &mut flexmap.catalog;
+    let values = flexmap.values.shared();
+
+    let states = process_par::<K, _, _, _, _>(paths, options.threads(), SeedSelector::<K,C,S,L>::new(options.seeds)?.context(),
+        |file, record| add_reference(&paths[file], file, catalog, record),
+        || Ok(MapState::new(SeedSelector::<K,C,S,L>::new(options.seeds)?, options.repeat_policy)),
+        |state, chunk| {
+            state.total_kmers += chunk.owned.len() as u64;
+            for seed in chunk.seeds(&mut state.selector) {
+                let (pos, cmer, strand) = (seed.pos, seed.core, seed.strand);
+
+                state.total_minimizers += 1;
+
+                let flanks = seed.kmer.flanks::<F>();
+
+                if let Some(range) = keys.vrange(cmer.0) {
+                    let cell = occurrence(layout, chunk.reference_id, chunk.offset + pos, strand)?;
+                    if capped.contains(&cmer.0) {
+                        state.capped.insert(cmer.0, cell, flanks.0 as u32);
+                    } else {
+                        values.write(range, cursors.next(slots.slot(cmer.0)), cell, flanks.0 as u32)?;
+                    }
+                }
             }
-        }
+            Ok(())
+        })?;
 
-    }
-    eprintln!("Minimizer compression rate: {} ({}/{})", total_minimizers as f64/total_kmers as f64, total_minimizers, total_kmers);
+    let keys = &flexmap.keys;
+    let part_ranges = |part: usize, parts: usize| {
+        let kmers = 1u64 << (2 * C);
+        keys.iter_range(kmers * part as u64 / parts as u64..kmers * (part as u64 + 1) / parts as u64).map(|(_, range)| range)
+    };
+    finish_map(&mut flexmap.values, states, |cmer| keys.vrange(cmer), part_ranges, options.threads())?;
+    flexmap.verify()?;
 
-    eprintln!("OwnRC {}", own_rc_count);
-    eprintln!("Number of ids: {}", id2reference.len());
+    eprintln!("Number of ids: {}", flexmap.catalog.len());
 
-    Ok((flexmap, reference2id, id2reference))
+    Ok(flexmap)
 }
 
 
@@ -304,96 +608,343 @@ fn hash_build_map<
     const S: usize,
     const L: usize,
     const HEADER_THRESHOLD: usize,
->(path: impl AsRef<Path>, keys: FMKeysHash) -> 
-        Result<(FlexmapHash<C, F, HEADER_THRESHOLD>, HashMap<String, usize>, Vec<String>), std::io::Error> {
+>(paths: &[PathBuf], keys: FMKeysHash, report: &BuildReport, options: &BuildOptions) -> 
+        Result<FlexmapHash<C, F, HEADER_THRESHOLD>, FlexmapError> {
+    let layout = options.layout;
+    let capped = capped_keys(report);
+
+    let mut flexmap = FlexmapHash::<C,F,HEADER_THRESHOLD>::with_layout(keys, layout);
+    flexmap.seeding = SeedSelector::<K,C,S,L>::new(options.seeds)?.params();
+    for path in paths {
+        flexmap.catalog.add_source(path);
+    }
+
+    let cursors = FillCursors::new(flexmap.keys.data.len());
+    let keys = &flexmap.keys;
+    let catalog = &mut flexmap.catalog;
+    let values = flexmap.values.shared();
+
+    let states = process_par::<K, _, _, _, _>(paths, options.threads(), SeedSelector::<K,C,S,L>::new(options.seeds)?.context(),
+        |file, record| add_reference(&paths[file], file, catalog, record),
+        || Ok(MapState::new(SeedSelector::<K,C,S,L>::new(options.seeds)?, options.repeat_policy)),
+        |state, chunk| {
+            state.total_kmers += chunk.owned.len() as u64;
+            for seed in chunk.seeds(&mut state.selector) {
+                let (pos, cmer, strand) = (seed.pos, seed.core, seed.strand);
+
+                state.total_minimizers += 1;
+
+                let flanks = seed.kmer.flanks::<F>();
+
+                if let Some(index) = keys.index(cmer.0) {
+                    let entry = &keys.data[index];
+                    if entry.is_empty() { continue };
+                    let range = (entry.range_start as usize, entry.range_start as usize + entry.range_len as usize);
+                    let cell = occurrence(layout, chunk.reference_id, chunk.offset + pos, strand)?;
+                    if capped.contains(&cmer.0) {
+                        state.capped.insert(cmer.0, cell, flanks.0 as u32);
+                    } else {
+                        values.write(range, cursors.next(index), cell, flanks.0 as u32)?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
 
-    let mut cs = ClosedSyncmer::<K,S,L>::new();
-    let file: File = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.as_ref().display(), why),
-        Ok(file) => file,
+    let keys = &flexmap.keys;
+    let part_ranges = |part: usize, parts: usize| {
+        let cells = keys.data.len();
+        FMKeysHash::iter_in(&keys.data[cells * part / parts..cells * (part + 1) / parts]).map(|(_, range)| range)
     };
+    finish_map(&mut flexmap.values, states, |cmer| keys.vrange(cmer), part_ranges, options.threads())?;
+    flexmap.verify()?;
 
-    let mut flexmap = FlexmapHash::<C,F,HEADER_THRESHOLD>::new(keys);
+    eprintln!("Number of ids: {}", flexmap.catalog.len());
 
-    let mut record = OwnedFastaRecord::new();
-    
-    let buffer_size = usize::pow(2, 24);
-    let mut byte_reader = Arc::new(Mutex::new(FastaByteReader::new(file, buffer_size)?));
-    let mut fasta_reader = FastaReader::with_capacity(buffer_size);
+    Ok(flexmap)
+}
 
-    let kmer_count = 0;
-    let mut own_rc_count = 0;
 
-    let mut reference_running_id = 1;
-    let mut reference2id: HashMap<String, usize> = HashMap::<String, usize>::new();
-    let mut id2reference = Vec::<String>::new();
-    id2reference.push("dummy".into());
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, File}, io::Write};
 
-    let mut total_kmers = 0;
-    let mut total_minimizers = 0;
-    
-    while let Some(()) = fasta_reader
-        .load_batch_par(&mut byte_reader)
-        .expect("Batch is invalid")
-    {
-
-        while let Some(_) = fasta_reader.next(&mut record) {
-            if !record.valid_extended() {
-                panic!("Record is not valid {:?}", record.to_string())
-            }
-            let iter = KmerIter::<K, true>::new(record.seq());
+    use flate2::{write::GzEncoder, Compression};
 
-            let header = String::from_utf8_lossy(&record.head()[1..]).split(' ').next().unwrap().to_string();
+    use crate::{flexmap::VRangeGetter, values::VRange};
 
-            let reference_id = match reference2id
-                    .try_insert(header.clone(), reference_running_id) {
-                Ok(reference_id) => *reference_id,
-                Err(_) => panic!("Header {:?} has been seen before!", record.head()),
-            };
-            id2reference.push(header.clone());
-            assert_eq!(id2reference[reference_id], header);
+    use super::*;
 
-            reference_running_id += 1;
+    /// A few pseudo random records, the last one repeats the first so that some keys occur often.
+    fn test_records() -> Vec<String> {
+        let mut state = 42u64;
+        let mut records: Vec<String> = (0..4).map(|_| (0..5000).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        }).collect()).collect();
+        records.push(records[0].clone());
+        records
+    }
+
+    fn write_records(writer: &mut impl Write, records: &[String], first: usize) {
+        for (idx, seq) in records.iter().enumerate() {
+            writeln!(writer, ">ref{} test record\n{}", first + idx, seq).unwrap();
+        }
+    }
 
+    fn test_fasta(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("flexmap_{}_{}.fa", name, std::process::id()));
+        write_records(&mut File::create(&path).unwrap(), &test_records(), 0);
+        path
+    }
 
-            // println!("{:?} -> {}", String::from_utf8_lossy(record.head()), reference_id);
+    #[test]
+    fn test_parallel_build_is_deterministic() {
+        let path = test_fasta("default");
+        let build = |threads| {
+            let options = BuildOptions { threads, ..BuildOptions::new(1000) };
+            default_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::file(&path), &options).unwrap().0
+        };
+        let single = build(1);
+        let multi = build(4);
+        fs::remove_file(&path).unwrap();
+
+        assert!(single.values.data.iter().any(|cell| !cell.empty()));
+        assert_eq!(single.keys.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), multi.keys.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(single.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), multi.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(single.catalog, multi.catalog);
+    }
 
-            for (pos, kmer_fwd, kmer_rev) in iter {
-                let cmer_fwd = kmer_fwd.middle::<C>();
-                let cmer_rev = kmer_rev.middle::<C>();
-                let kmer = if cmer_fwd < cmer_rev { kmer_fwd } else { kmer_rev };
-                let cmer = min(cmer_fwd, cmer_rev);
+    #[test]
+    fn test_parallel_hash_build_is_deterministic() {
+        let path = test_fasta("hash");
+        let build = |threads| {
+            let options = BuildOptions { threads, ..BuildOptions::new(1000) };
+            hash_build::<13, 5, 8, 3, 3, 2>(&BuildInput::file(&path), &options).unwrap().0
+        };
+        let single = build(1);
+        let multi = build(4);
+        fs::remove_file(&path).unwrap();
+
+        let entries = |keys: &FMKeysHash| keys.data.iter().map(|e| (e.key, e.range_start, e.range_len)).collect::<Vec<_>>();
+        assert_eq!(entries(&single.keys), entries(&multi.keys));
+        assert_eq!(single.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), multi.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(single.catalog, multi.catalog);
+    }
 
-                total_kmers += 1;
+    #[test]
+    fn test_hash_build_matches_default_build() {
+        let path = test_fasta("hash_direct");
+        let input = BuildInput::file(&path);
+        let vrange = |vrange: Option<VRange>| vrange.map(|vrange| (
+            vrange.header.map(|header| header.iter().map(|flank| flank.get()).collect::<Vec<_>>()),
+            vrange.positions.iter().map(|cell| cell.0).collect::<Vec<_>>(),
+        ));
+        for options in [
+            BuildOptions { threads: 2, ..BuildOptions::new(1000) },
+            BuildOptions { threads: 2, ..BuildOptions::new(1) },
+            BuildOptions { threads: 2, repeat_policy: RepeatPolicy::Subsample { cap: 3 }, ..BuildOptions::new(1) },
+        ] {
+            let (direct, direct_report) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &options).unwrap();
+            let (hash, hash_report) = hash_build::<13, 5, 8, 3, 3, 2>(&input, &options).unwrap();
+
+            assert_eq!(hash_report, direct_report);
+            for cmer in 0..1u64 << (2 * 5) {
+                assert_eq!(vrange(hash.get_vrange(cmer)), vrange(direct.get_vrange(cmer)), "core {}", cmer);
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
 
-                if !cs.is_minimizer(cmer.0) { continue };
+    #[test]
+    fn test_convert_backends() {
+        let path = test_fasta("convert");
+        let input = BuildInput::file(&path);
+        let options = BuildOptions { threads: 2, repeat_policy: RepeatPolicy::Subsample { cap: 3 }, ..BuildOptions::new(1) };
+        let (direct, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &options).unwrap();
+        let (hash, _) = hash_build::<13, 5, 8, 3, 3, 2>(&input, &options).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let converted = hash.to_direct::<16>().unwrap();
+        assert_eq!(converted.keys.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), direct.keys.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(converted.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), direct.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(converted.catalog, direct.catalog);
+
+        let converted = direct.to_hash().unwrap();
+        let positions = |vrange: VRange| vrange.positions.iter().map(|cell| cell.0).collect::<Vec<_>>();
+        assert_eq!(converted.keys.len(), hash.keys.len());
+        for (cmer, vrange) in hash.iter() {
+            assert_eq!(converted.get_vrange(cmer).map(positions), Some(positions(vrange)));
+        }
 
-                total_minimizers += 1;
+        assert!(matches!(FlexmapHash::<17, 8, 2>::new(FMKeysHash::with_capacity(1)).to_direct::<16>(), Err(FlexmapError::Overflow { .. })));
+    }
 
-                // if cmer.is_own_rc() { 
-                //     own_rc_count += 1;
-                //     continue 
-                // };
+    #[test]
+    fn test_seed_schemes() {
+        let path = test_fasta("schemes");
+        let input = BuildInput::file(&path);
+        let mut counts = Vec::new();
+        for seeds in [SeedScheme::All, SeedScheme::ClosedSyncmer, SeedScheme::OpenSyncmer { offset: 1 }, SeedScheme::Minimizer { window: 5 }] {
+            let options = BuildOptions { threads: 2, seeds, ..BuildOptions::new(1000) };
+            let (direct, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &options).unwrap();
+            let (hash, _) = hash_build::<13, 5, 8, 3, 3, 2>(&input, &options).unwrap();
+            let expected = SeedSelector::<13, 5, 3, 3>::new(seeds).unwrap().params();
+            assert_eq!(direct.seeding, expected);
+            assert_eq!(hash.seeding, expected);
+            assert_eq!(direct.iter().map(|(_, vrange)| vrange.positions.len()).sum::<usize>(), hash.iter().map(|(_, vrange)| vrange.positions.len()).sum::<usize>());
+            counts.push(direct.iter().map(|(_, vrange)| vrange.positions.len()).sum::<usize>());
+        }
+        fs::remove_file(&path).unwrap();
 
-                let flanks = kmer.flanks::<F>();
-        
+        // Every k-mer is indexed only by the all k-mers scheme.
+        assert_eq!(counts[0], 5 * (5000 - 13 + 1));
+        assert!(counts[1..].iter().all(|&count| count > 0 && count <= counts[0]));
+        assert!(counts[2] < counts[0] && counts[3] < counts[0]);
+    }
 
-                match flexmap.keys.vrange(cmer.0 as u64) {
-                    Some(range) => {
-                        let mut vblock = flexmap.values.get_range_mut((range.0 as usize, range.1 as usize));
-                        vblock.insert(VD::set(reference_id as u64, pos as u64), flanks.0 as u32);
-                    },
-                    None => {},
-                };
+    #[test]
+    fn test_minimizers_across_chunks() {
+        let mut state = 7u64;
+        let seq: String = (0..2 * CHUNK_SIZE + 1000).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        }).collect();
+        let path = std::env::temp_dir().join(format!("flexmap_chunks_{}.fa", std::process::id()));
+        write_records(&mut File::create(&path).unwrap(), &[seq.clone()], 0);
+        let options = BuildOptions { threads: 2, seeds: SeedScheme::Minimizer { window: 10 }, ..BuildOptions::new(1 << 15) };
+        let (flexmap, report) = default_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::file(&path), &options).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(report.dropped.is_empty());
+
+        let mut indexed = Vec::new();
+        for (core, vrange) in flexmap.iter() {
+            vrange.all_matches(|rpos, _, strand| indexed.push((core, rpos as usize, strand == Strand::Forward)));
+        }
+        let mut expected: Vec<_> = flexmap.seeds::<13, 3, 3>(seq.as_bytes()).unwrap()
+            .map(|seed| (seed.core, seed.pos, seed.strand == Strand::Forward))
+            .collect();
+        indexed.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(indexed.len(), expected.len());
+        assert!(indexed == expected);
+    }
 
-                // if reference_id == 1 && pos == 578574;
-            }
+    #[test]
+    fn test_query_read() {
+        let path = test_fasta("query");
+        let options = BuildOptions { threads: 2, seeds: SeedScheme::OpenSyncmer { offset: 1 }, ..BuildOptions::new(1000) };
+        let (flexmap, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::file(&path), &options).unwrap();
+        let hash = flexmap.to_hash().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // A read taken from the second reference finds itself at every seed.
+        let records = test_records();
+        let read = &records[1].as_bytes()[100..250];
+        let seeds: Vec<_> = flexmap.seeds::<13, 3, 3>(read).unwrap().collect();
+        let hits: Vec<_> = flexmap.query_read::<13, 3, 3>(read).unwrap().collect();
+        assert!(!seeds.is_empty());
+        assert_eq!(hits.iter().map(|(seed, _)| *seed).collect::<Vec<_>>(), seeds);
+        assert_eq!(hash.seeds::<13, 3, 3>(read).unwrap().collect::<Vec<_>>(), seeds);
+        let reference = flexmap.catalog.id("ref1").unwrap() as u64;
+        for (seed, vrange) in &hits {
+            let mut found = false;
+            vrange.best_flex_match::<8, _>(&Kmer(seed.flanks as u64), |rpos, value, strand, dist| {
+                found |= (value, rpos, strand) == (reference, 100 + seed.pos as u64, seed.strand) && dist.map_or(true, |(dist, _)| dist.total() == 0);
+            });
+            assert!(found, "seed at {} not found", seed.pos);
         }
 
+        // Open syncmers only depend on the core, so the reverse complement has
+        // the same seeds on the other strand.
+        let reverse: Vec<u8> = read.iter().rev().map(|base| match base { b'A' => b'T', b'C' => b'G', b'G' => b'C', _ => b'A' }).collect();
+        let mut cores: Vec<_> = seeds.iter().map(|seed| (seed.core, seed.strand == Strand::Forward)).collect();
+        let mut reverse_cores: Vec<_> = flexmap.seeds::<13, 3, 3>(&reverse).unwrap().map(|seed| (seed.core, seed.strand != Strand::Forward)).collect();
+        cores.sort_unstable();
+        reverse_cores.sort_unstable();
+        assert_eq!(cores, reverse_cores);
+
+        assert!(flexmap.seeds::<15, 3, 3>(read).is_err());
     }
-    eprintln!("Minimizer compression rate: {}", total_minimizers as f64/total_kmers as f64);
 
-    eprintln!("OwnRC {}", own_rc_count);
+    #[test]
+    fn test_directory_build() {
+        let path = test_fasta("single");
+        let dir = std::env::temp_dir().join(format!("flexmap_dir_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let records = test_records();
+        write_records(&mut File::create(dir.join("1.fa")).unwrap(), &records[..2], 0);
+        let mut gz = GzEncoder::new(File::create(dir.join("2.fna.gz")).unwrap(), Compression::default());
+        write_records(&mut gz, &records[2..], 2);
+        gz.finish().unwrap();
+
+        let options = BuildOptions { threads: 2, ..BuildOptions::new(1000) };
+        let (single, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::file(&path), &options).unwrap();
+        let (split, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::Directory(dir.clone()), &options).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(single.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), split.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(split.catalog.len(), 5);
+        assert_eq!(split.catalog.id("ref3"), Some(4));
+        assert_eq!(split.catalog.source(1), Some(dir.join("1.fa").to_string_lossy().as_ref()));
+        assert_eq!(split.catalog.source(4), Some(dir.join("2.fna.gz").to_string_lossy().as_ref()));
+    }
+
+    #[test]
+    fn test_spill_build_matches_default_build() {
+        let path = test_fasta("spill");
+        let options = BuildOptions { threads: 3, ..BuildOptions::new(1000) };
+        // Small budget, so that every worker writes several runs.
+        let spill = SpillOptions::new(std::env::temp_dir(), 3 * 1000 * SeedRecord::BYTES);
+        let (two_pass, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::file(&path), &options).unwrap();
+        let (single_pass, _) = spill_build::<13, 5, 8, 3, 3, 16, 2>(&BuildInput::file(&path), &options, &spill).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(two_pass.keys.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), single_pass.keys.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(two_pass.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), single_pass.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(two_pass.catalog, single_pass.catalog);
+    }
 
-    Ok((flexmap, reference2id, id2reference))
+    #[test]
+    fn test_repeat_policy() {
+        // The last record repeats the first one, so many keys occur twice.
+        let path = test_fasta("repeats");
+        let input = BuildInput::file(&path);
+        let options = BuildOptions { threads: 3, ..BuildOptions::new(1) };
+        let (_, dropped) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &options).unwrap();
+
+        let options = BuildOptions { repeat_policy: RepeatPolicy::Subsample { cap: 1 }, ..options };
+        let (single, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &BuildOptions { threads: 1, ..options }).unwrap();
+        let (multi, report) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &options).unwrap();
+        let spill = SpillOptions::new(std::env::temp_dir(), 1 << 20);
+        let (spilled, spill_report) = spill_build::<13, 5, 8, 3, 3, 16, 2>(&input, &options, &spill).unwrap();
+        let (full, _) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &BuildOptions::new(1000)).unwrap();
+        // Keys above max_range_size but within cap are kept completely and not reported.
+        let within = BuildOptions { repeat_policy: RepeatPolicy::Subsample { cap: 3 }, ..options };
+        let (kept, kept_report) = default_build::<13, 5, 8, 3, 3, 16, 2>(&input, &within).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(!dropped.dropped.is_empty());
+        assert_eq!(report.subsampled, dropped.dropped);
+        assert_eq!(spill_report, report);
+        for (key, _) in &report.subsampled {
+            let vrange = multi.values.get_range(multi.keys.vrange(*key).unwrap());
+            let mut matches = Vec::new();
+            vrange.all_matches(|rpos, rval, _| matches.push((rval, rpos)));
+            assert_eq!(matches.len(), 1);
+            let mut occurrences = Vec::new();
+            full.values.get_range(full.keys.vrange(*key).unwrap()).all_matches(|rpos, rval, _| occurrences.push((rval, rpos)));
+            assert!(occurrences.contains(&matches[0]));
+        }
+        for &(key, count) in &dropped.dropped {
+            let vrange = kept.values.get_range(kept.keys.vrange(key).unwrap());
+            let mut matches = Vec::new();
+            vrange.all_matches(|rpos, rval, _| matches.push((rval, rpos)));
+            assert_eq!(matches.len(), min(count, 3) as usize);
+            assert_eq!(kept_report.subsampled.contains(&(key, count)), count > 3);
+        }
+        assert_eq!(single.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), multi.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+        assert_eq!(spilled.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), multi.values.data.iter().map(|cell| cell.0).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+
+use crate::format::{FormatError, SectionId};
+
+/// Describes one reference sequence. The reference id stored in VD is the
+/// index of this entry in the catalog.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Savefile, Encode, Decode)]
+pub struct ReferenceEntry {
+    pub name: String,
+    pub length: u64,
+    pub description: String,
+    /// User defined group, e.g. a taxon id.
+    pub group: Option<u64>,
+    /// Index of the file the reference was read from in ReferenceCatalog::sources.
+    pub source: Option<usize>,
+}
+
+/// Maps reference ids to names (and back). Id 0 is reserved so that an empty
+/// VCell (0) can never be mistaken for an occurrence.
+#[derive(Clone, Debug, PartialEq, Eq, Savefile)]
+pub struct ReferenceCatalog {
+    entries: Vec<ReferenceEntry>,
+    /// Not encoded, rebuilt from entries on decode.
+    name2id: HashMap<String, usize>,
+    sources: Vec<String>,
+}
+
+impl Default for ReferenceCatalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReferenceCatalog {
+    pub fn new() -> Self {
+        ReferenceCatalog {
+            entries: vec![ReferenceEntry { name: "dummy".into(), ..Default::default() }],
+            name2id: HashMap::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Registers an input file and returns its index for ReferenceEntry::source.
+    pub fn add_source(&mut self, path: &Path) -> usize {
+        self.sources.push(path.to_string_lossy().into_owned());
+        self.sources.len() - 1
+    }
+
+    /// Input files of the build, in build order.
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    /// File reference id was read from.
+    pub fn source(&self, id: usize) -> Option<&str> {
+        let source = self.get(id)?.source?;
+        self.sources.get(source).map(|path| path.as_str())
+    }
+
+    /// Adds a reference and returns its id. Gives the entry back if the name is already taken.
+    pub fn insert(&mut self, entry: ReferenceEntry) -> Result<usize, ReferenceEntry> {
+        let id = self.entries.len();
+        if self.name2id.try_insert(entry.name.clone(), id).is_err() {
+            return Err(entry);
+        }
+        self.entries.push(entry);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&ReferenceEntry> {
+        if id == 0 { return None };
+        self.entries.get(id)
+    }
+
+    pub fn id(&self, name: &str) -> Option<usize> {
+        self.name2id.get(name).copied()
+    }
+
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.get(id).map(|entry| entry.name.as_str())
+    }
+
+    pub fn set_group(&mut self, id: usize, group: Option<u64>) -> Option<()> {
+        if id == 0 { return None };
+        self.entries.get_mut(id)?.group = group;
+        Some(())
+    }
+
+    /// Number of references (without the reserved id 0).
+    pub fn len(&self) -> usize {
+        self.entries.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over (id, entry) of all references.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &ReferenceEntry)> {
+        self.entries.iter().enumerate().skip(1)
+    }
+
+    /// Catalog of decoded entries (including the reserved one) and sources.
+    /// None if there is no reserved entry or two references share a name.
+    fn from_parts(entries: Vec<ReferenceEntry>, sources: Vec<String>) -> Option<Self> {
+        if entries.is_empty() { return None };
+        let mut name2id = HashMap::with_capacity(entries.len() - 1);
+        for (id, entry) in entries.iter().enumerate().skip(1) {
+            name2id.try_insert(entry.name.clone(), id).ok()?;
+        }
+        Some(ReferenceCatalog { entries, name2id, sources })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).expect("Catalog can be encoded")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        match bincode::decode_from_slice(bytes, bincode::config::standard()) {
+            Ok((catalog, _)) => Ok(catalog),
+            Err(_) => Err(FormatError::InvalidSection(SectionId::Catalog)),
+        }
+    }
+}
+
+// name2id is left out so that the encoding does not depend on the iteration
+// order of the HashMap and does not repeat the names.
+impl Encode for ReferenceCatalog {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.entries.encode(encoder)?;
+        self.sources.encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for ReferenceCatalog {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let entries = Vec::<ReferenceEntry>::decode(decoder)?;
+        let sources = Vec::<String>::decode(decoder)?;
+        Self::from_parts(entries, sources).ok_or(DecodeError::Other("reference names are not unique"))
+    }
+}
+bincode::impl_borrow_decode!(ReferenceCatalog);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, length: u64) -> ReferenceEntry {
+        ReferenceEntry { name: name.into(), length, description: format!("{} description", name), group: None, source: None }
+    }
+
+    #[test]
+    fn test_catalog_ids() {
+        let mut catalog = ReferenceCatalog::new();
+        assert_eq!(catalog.insert(entry("chr1", 100)), Ok(1));
+        assert_eq!(catalog.insert(entry("chr2", 50)), Ok(2));
+        assert_eq!(catalog.insert(entry("chr1", 10)), Err(entry("chr1", 10)));
+
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog.id("chr2"), Some(2));
+        assert_eq!(catalog.name(1), Some("chr1"));
+        assert_eq!(catalog.get(0), None);
+        assert_eq!(catalog.get(1).unwrap().length, 100);
+    }
+
+    #[test]
+    fn test_catalog_roundtrip() {
+        let mut catalog = ReferenceCatalog::new();
+        let source = catalog.add_source(Path::new("genomes/ecoli.fna.gz"));
+        catalog.insert(entry("chr1", 100)).unwrap();
+        catalog.insert(ReferenceEntry { source: Some(source), ..entry("plasmid", 5) }).unwrap();
+        catalog.set_group(2, Some(562));
+
+        let decoded = ReferenceCatalog::from_bytes(&catalog.to_bytes()).unwrap();
+        assert_eq!(decoded, catalog);
+        assert_eq!(decoded.get(2).unwrap().group, Some(562));
+        assert_eq!(decoded.source(1), None);
+        assert_eq!(decoded.source(2), Some("genomes/ecoli.fna.gz"));
+        assert_eq!(decoded.id("plasmid"), Some(2));
+
+        // The same references give the same bytes.
+        let build = || {
+            let mut catalog = ReferenceCatalog::new();
+            for idx in 0..100 {
+                catalog.insert(entry(&format!("contig{}", idx), idx)).unwrap();
+            }
+            catalog.to_bytes()
+        };
+        assert_eq!(build(), build());
+
+        let duplicate = bincode::encode_to_vec((vec![entry("dummy", 0), entry("chr1", 1), entry("chr1", 2)], Vec::<String>::new()), bincode::config::standard()).unwrap();
+        assert!(matches!(ReferenceCatalog::from_bytes(&duplicate), Err(FormatError::InvalidSection(SectionId::Catalog))));
+    }
+}
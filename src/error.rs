@@ -0,0 +1,100 @@
+use std::fmt::{Debug, Display};
+use std::path::{Path, PathBuf};
+
+use crate::format::FormatError;
+use crate::seeds::SeedScheme;
+
+/// Error type of all public entry points (build, load, save).
+#[derive(Debug)]
+pub enum FlexmapError {
+    /// Reading or writing a file failed. path is None if the error did not originate from a named file.
+    Io { path: Option<PathBuf>, source: std::io::Error },
+    /// A record contains characters that are not valid nucleotides.
+    InvalidSequence { path: PathBuf, reference: String },
+    /// Two records share the same reference name.
+    DuplicateReference { path: PathBuf, reference: String },
+    /// A value does not fit into the space the data structure reserves for it.
+    Overflow { what: &'static str, value: u64, max: u64 },
+    /// More occurrences of a key than slots were reserved for it.
+    BlockFull { capacity: usize },
+    /// Fewer occurrences of a key than slots were reserved for it.
+    IncompleteBlock { core: u64, filled: usize, capacity: usize },
+    /// A saved index does not match the type or format it is loaded into.
+    FormatMismatch(FormatError),
+    /// A hash table load factor that is not in (0, 1).
+    InvalidLoadFactor(f64),
+    /// A seed scheme that cannot select seeds for the core and s-mer sizes of the index.
+    InvalidSeedScheme(SeedScheme),
+}
+
+impl FlexmapError {
+    pub fn io(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        FlexmapError::Io { path: Some(path.as_ref().to_path_buf()), source }
+    }
+
+    /// Wraps parser errors of the FASTA readers, which are not io::Errors.
+    pub fn parse<E: Debug>(path: impl AsRef<Path>, error: E) -> Self {
+        let source = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", error));
+        FlexmapError::io(path, source)
+    }
+
+    pub fn overflow(what: &'static str, value: u64, max: u64) -> Self {
+        FlexmapError::Overflow { what, value, max }
+    }
+}
+
+impl Display for FlexmapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlexmapError::Io { path: Some(path), source } => write!(f, "{}: {}", path.display(), source),
+            FlexmapError::Io { path: None, source } => write!(f, "{}", source),
+            FlexmapError::InvalidSequence { path, reference } => {
+                write!(f, "{}: record {:?} is not a valid sequence", path.display(), reference)
+            }
+            FlexmapError::DuplicateReference { path, reference } => {
+                write!(f, "{}: reference {:?} has been seen before", path.display(), reference)
+            }
+            FlexmapError::Overflow { what, value, max } => {
+                write!(f, "{} {} exceeds the maximum of {}", what, value, max)
+            }
+            FlexmapError::BlockFull { capacity } => {
+                write!(f, "value block with {} slots is already full", capacity)
+            }
+            FlexmapError::IncompleteBlock { core, filled, capacity } => {
+                write!(f, "value block of core {} has only {} of {} slots filled", core, filled, capacity)
+            }
+            FlexmapError::FormatMismatch(e) => write!(f, "{}", e),
+            FlexmapError::InvalidLoadFactor(load_factor) => {
+                write!(f, "load factor {} is not in (0, 1)", load_factor)
+            }
+            FlexmapError::InvalidSeedScheme(scheme) => {
+                write!(f, "seed scheme {:?} cannot select seeds", scheme)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlexmapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FlexmapError::Io { source, .. } => Some(source),
+            FlexmapError::FormatMismatch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FlexmapError {
+    fn from(source: std::io::Error) -> Self {
+        FlexmapError::Io { path: None, source }
+    }
+}
+
+impl From<FormatError> for FlexmapError {
+    fn from(e: FormatError) -> Self {
+        match e {
+            FormatError::Io(source) => FlexmapError::Io { path: None, source },
+            e => FlexmapError::FormatMismatch(e),
+        }
+    }
+}
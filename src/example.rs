@@ -2,7 +2,7 @@ use std::{cmp::min, collections::HashMap};
 
 use kmerrs::consecutive::kmer::{Kmer, KmerIter};
 
-use crate::{flexmap::{self, FMKeysSmall, Flexmap, VRangeGetter}, keys::FMKeys, values::VData, VD};
+use crate::{flexmap::{self, FMKeysSmall, Flexmap, VRangeGetter}, keys::{FMKeys, RepeatPolicy}, values::{FillCursors, Strand, VData}, VD};
 
 
 pub fn build_keys() -> FMKeys::<3, 8> {
@@ -16,7 +16,7 @@ pub fn build_keys() -> FMKeys::<3, 8> {
         keys.get_kmer_cell_mut_ref(kmer.middle::<C>().0).increment();
     }
 
-    keys.build::<2>(1000);
+    keys.build::<2>(1000, RepeatPolicy::Drop);
 
     keys
 }
@@ -34,17 +34,18 @@ pub fn build_flexmap() -> Flexmap<3,8,8,2> {
     let seq = "CATCGATCGTACGTGACTGCGTCGTCCTGCGTCGTCGTCGTGCTGCTGCTGTCGTCGTCGTCGTGCTGTCGTCGTA";
 
     let kiter = KmerIter::<K, true>::new(seq.as_bytes());
-
+    let cursors = FillCursors::new(1 << (2 * C));
 
     for (pos, kmer_fwd, kmer_rev) in kiter.clone() {
         let kmer = min(kmer_fwd, kmer_rev);
+        let strand = if kmer_fwd <= kmer_rev { Strand::Forward } else { Strand::Reverse };
         let core = kmer.middle::<C>();
         let flanks = kmer.flanks::<F>();
 
         match flexmap.keys.vrange(core.0) {
             Some(range) => {
                 let mut vblock = flexmap.values.get_range_mut(range);
-                vblock.insert(VD::set(1, pos as u64), flanks.0 as u32);
+                vblock.insert(cursors.next(core.0 as usize), 1, pos as u64, strand, flanks.0 as u32).expect("Value fits block");
                 println!("Insert {}\n{}", core.to_string().expect("Error"), vblock);
             },
             None => todo!(),
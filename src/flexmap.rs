@@ -1,12 +1,21 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::io::BufWriter;
+use std::mem;
+use std::ops::Range;
 use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use std::process::exit;
 use std::ptr;
 
-use crate::keys::{FMKeys, FMKeysHash};
-use crate::values::{FMValues, VRange};
+use crate::catalog::ReferenceCatalog;
+use crate::error::FlexmapError;
+use crate::format::{self, FormatError, IndexHeader, IndexKind, IndexParams, SectionId, SeedParams};
+use crate::keys::{FMKeys, FMKeysHash, RepeatPolicy};
+use crate::layout::BlockLayout;
+use crate::seeds::{self, ReadSeed};
+use crate::values::{FMValues, VRange, ValueLayout};
 
 pub type FlexmapStd = Flexmap<15, 16, 16, 2>;
 pub type FMKeysStd = FMKeys<15, 16>;
@@ -17,6 +26,9 @@ pub type FMKeysSmall = FMKeys<3, 16>;
 
 pub type KeysHashSmall = HashMap<u32, (u32, u32)>;
 
+/// Longest core a direct key table is built for, the table has 4^C cells.
+pub const MAX_DIRECT_C: usize = 16;
+
 use bincode::{Decode, Encode};
 use bioreader::utils::{time, time_noerr};
 use savefile::prelude::*;
@@ -32,6 +44,51 @@ pub trait VRangeGetter {
     fn get_vrange(&self, canonical_kmer: u64) -> Option<VRange>;
 }
 
+/// Everything code that uses an index needs, implemented by all backends
+/// (direct and hash, in memory and mapped), so that it can be generic over
+/// the backend instead of depending on the const generics of one type.
+pub trait FlexIndex: VRangeGetter + Sync {
+    /// Parameters of the index: kind, core length C, flank length F,
+    /// HEADER_THRESHOLD, value layout and seed scheme.
+    fn index_params(&self) -> IndexParams;
+
+    fn catalog(&self) -> &ReferenceCatalog;
+
+    /// Number of cores that have values. Walks all keys of a direct table.
+    fn key_count(&self) -> usize {
+        self.iter_vranges().count()
+    }
+
+    /// All cores that have values, with their values.
+    fn iter_vranges(&self) -> Box<dyn Iterator<Item = (u64, VRange<'_>)> + Send + '_>;
+
+    /// Bytes taken by keys and values.
+    fn memory_usage(&self) -> usize;
+
+    /// Checks that every reserved value slot holds an occurrence, i.e. that
+    /// the build selected the same seeds when counting and when filling.
+    fn verify(&self) -> Result<(), FlexmapError> {
+        for (core, vrange) in self.iter_vranges() {
+            let filled = vrange.positions.iter().filter(|cell| !cell.empty()).count();
+            if filled != vrange.positions.len() {
+                return Err(FlexmapError::IncompleteBlock { core, filled, capacity: vrange.positions.len() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up several cores at once, in the given order.
+    fn get_vranges(&self, canonical_kmers: &[u64]) -> Vec<Option<VRange<'_>>> {
+        canonical_kmers.iter().map(|&kmer| self.get_vrange(kmer)).collect()
+    }
+}
+
+/// Splits 0..len into at most chunks contiguous ranges of about the same size.
+fn chunk_ranges(len: usize, chunks: usize) -> impl Iterator<Item = Range<usize>> {
+    let size = len.div_ceil(chunks.max(1)).max(1);
+    (0..len).step_by(size).map(move |start| start..(start + size).min(len))
+}
+
 pub trait DBBuilder {
     fn build(options: impl FlexOptions) -> Self;
 }
@@ -68,7 +125,7 @@ pub trait DBBuilder {
 // / The interface for this is provided with the crate kmerrs
 
 
-#[derive(Clone, Savefile, Encode, Decode, ser_raw::Serialize)]
+#[derive(Clone, Savefile, Encode, Decode)]
 #[repr(C)]
 pub struct Flexmap<
     const C: usize,
@@ -78,6 +135,8 @@ pub struct Flexmap<
 > {
     pub keys: FMKeys<C, CELLS_PER_BODY>,
     pub values: FMValues<F, HEADER_THRESHOLD>,
+    pub seeding: SeedParams,
+    pub catalog: ReferenceCatalog,
 }
 
 impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize>
@@ -85,12 +144,120 @@ impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THR
 {
     pub fn new(
         keys: FMKeys<C, CELLS_PER_BODY>,
+    ) -> Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD> {
+        Self::with_layout(keys, ValueLayout::default())
+    }
+
+    pub fn with_layout(
+        keys: FMKeys<C, CELLS_PER_BODY>,
+        layout: ValueLayout,
     ) -> Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD> {
         let size = keys.get_values_size();
         Flexmap {
             keys: keys,
-            values: FMValues::new(size),
+            values: FMValues::with_layout(size, layout),
+            seeding: SeedParams::default(),
+            catalog: ReferenceCatalog::new(),
+        }
+    }
+
+    /// Parameters recorded in the header of a saved index of this type.
+    pub fn params(seeding: SeedParams, layout: ValueLayout) -> IndexParams {
+        IndexParams {
+            kind: IndexKind::Direct,
+            c: C as u32,
+            f: F as u32,
+            cells_per_body: CELLS_PER_BODY,
+            header_threshold: HEADER_THRESHOLD as u32,
+            layout,
+            seeding,
+        }
+    }
+
+    /// Writes the index into a single self-describing file (see format.rs).
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), FlexmapError> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path).map_err(|e| FlexmapError::io(path, e))?);
+        let header = IndexHeader::new(Self::params(self.seeding, self.values.layout), 0.0);
+        format::write_index(&mut writer, header, &[
+            (SectionId::Keys, format::as_bytes(&self.keys.data)),
+            (SectionId::Overflow, format::as_bytes(&self.keys.overflow)),
+            (SectionId::Values, format::as_bytes(&self.values.data)),
+            (SectionId::Catalog, &self.catalog.to_bytes()),
+        ])?;
+        Ok(())
+    }
+
+    /// Loads an index written by save. Fails if the file was built with
+    /// different parameters than the const generics of this type.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FlexmapError> {
+        let path = path.as_ref();
+        let mut file = File::open(path).map_err(|e| FlexmapError::io(path, e))?;
+        let header = format::read_header(&mut file)?;
+        header.check(&Self::params(header.params.seeding, header.params.layout))?;
+
+        let keys = FMKeys {
+            data: format::read_section(&mut file, header.section(SectionId::Keys)?)?,
+            overflow: format::read_section(&mut file, header.section(SectionId::Overflow)?)?,
+        };
+        if keys.data.len() as u64 != FMKeys::<C, CELLS_PER_BODY>::table_size() {
+            return Err(FormatError::Truncated.into());
+        }
+        let values = FMValues {
+            data: format::read_section(&mut file, header.section(SectionId::Values)?)?,
+            layout: header.params.layout,
+        };
+        if values.data.len() != keys.get_values_size() {
+            return Err(FormatError::InvalidSection(SectionId::Values).into());
+        }
+        let catalog = ReferenceCatalog::from_bytes(&format::read_section::<u8>(&mut file, header.section(SectionId::Catalog)?)?)?;
+
+        Ok(Flexmap { keys, values, seeding: header.params.seeding, catalog })
+    }
+}
+
+impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize>
+    Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>
+{
+    /// All cores that have values, with their values, in core order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, VRange<'_>)> + Send + '_ {
+        self.keys.iter().map(|(kmer, range)| (kmer, self.values.get_range(range)))
+    }
+
+    /// Seeds of read, selected with the scheme and parameters the index was
+    /// built with. K, S and L have to match the build.
+    pub fn seeds<'a, const K: usize, const S: usize, const L: usize>(&self, read: &'a [u8]) -> Result<impl Iterator<Item = ReadSeed> + 'a, FlexmapError> {
+        seeds::read_seeds::<K, C, F, S, L>(&self.seeding, read)
+    }
+
+    /// Seeds of read that occur in the index, with their values.
+    pub fn query_read<'a, const K: usize, const S: usize, const L: usize>(&'a self, read: &'a [u8]) -> Result<impl Iterator<Item = (ReadSeed, VRange<'a>)> + 'a, FlexmapError> {
+        Ok(self.seeds::<K, S, L>(read)?.filter_map(|seed| Some((seed, self.get_vrange(seed.core)?))))
+    }
+
+    /// The same index with a hash table as keys. Values are laid out in core order.
+    pub fn to_hash(&self) -> Result<FlexmapHash<C, F, HEADER_THRESHOLD>, FlexmapError> {
+        let ranges: Vec<(u64, (usize, usize))> = self.keys.iter().collect();
+        let size = ranges.iter().map(|(_, (start, end))| end - start).sum();
+
+        let mut keys = FMKeysHash::with_expected_keys(ranges.len());
+        let mut values = FMValues::with_layout(size, self.values.layout);
+        let mut running_v = 0;
+        for (kmer, (start, end)) in ranges {
+            keys.insert(kmer, running_v as u64, (end - start) as u32)?;
+            values.data[running_v..running_v + end - start].clone_from_slice(&self.values.data[start..end]);
+            running_v += end - start;
         }
+        Ok(FlexmapHash { keys, values, seeding: self.seeding, catalog: self.catalog.clone() })
+    }
+
+    /// Splits iter into at most chunks iterators over disjoint core ranges, e.g.
+    /// to walk the index on several threads.
+    pub fn chunks(&self, chunks: usize) -> impl Iterator<Item = impl Iterator<Item = (u64, VRange<'_>)> + Send + '_> + '_ {
+        chunk_ranges(1 << (2 * C), chunks).map(move |kmers| {
+            self.keys.iter_range(kmers.start as u64..kmers.end as u64)
+                .map(move |(kmer, range)| (kmer, self.values.get_range(range)))
+        })
     }
 }
 
@@ -113,6 +280,8 @@ pub struct FlexmapHash<
 > {
     pub keys: FMKeysHash,
     pub values: FMValues<F, HEADER_THRESHOLD>,
+    pub seeding: SeedParams,
+    pub catalog: ReferenceCatalog,
 }
 
 impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize>
@@ -120,14 +289,70 @@ FlexmapHash<C, F, HEADER_THRESHOLD>
 {
     pub fn new(
         keys: FMKeysHash,
+    ) -> FlexmapHash<C, F, HEADER_THRESHOLD> {
+        Self::with_layout(keys, ValueLayout::default())
+    }
+
+    pub fn with_layout(
+        keys: FMKeysHash,
+        layout: ValueLayout,
     ) -> FlexmapHash<C, F, HEADER_THRESHOLD> {
         let size = keys.data.iter().fold(0, |acc, entry| {
             acc + entry.range_len
         });
         FlexmapHash {
             keys,
-            values: FMValues::new(size as usize),
+            values: FMValues::with_layout(size as usize, layout),
+            seeding: SeedParams::default(),
+            catalog: ReferenceCatalog::new(),
+        }
+    }
+
+    /// Parameters recorded in the header of a saved index of this type.
+    pub fn params(seeding: SeedParams, layout: ValueLayout) -> IndexParams {
+        IndexParams {
+            kind: IndexKind::Hash,
+            c: C as u32,
+            f: F as u32,
+            cells_per_body: 0,
+            header_threshold: HEADER_THRESHOLD as u32,
+            layout,
+            seeding,
+        }
+    }
+
+    /// Writes the index into a single self-describing file (see format.rs).
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), FlexmapError> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path).map_err(|e| FlexmapError::io(path, e))?);
+        let header = IndexHeader::new(Self::params(self.seeding, self.values.layout), self.keys.load_factor);
+        format::write_index(&mut writer, header, &[
+            (SectionId::Keys, format::as_bytes(&self.keys.data)),
+            (SectionId::Values, format::as_bytes(&self.values.data)),
+            (SectionId::Catalog, &self.catalog.to_bytes()),
+        ])?;
+        Ok(())
+    }
+
+    /// Loads an index written by save. Fails if the file was built with
+    /// different parameters than the const generics of this type.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FlexmapError> {
+        let path = path.as_ref();
+        let mut file = File::open(path).map_err(|e| FlexmapError::io(path, e))?;
+        let header = format::read_header(&mut file)?;
+        header.check(&Self::params(header.params.seeding, header.params.layout))?;
+
+        let keys = FMKeysHash::from_data(format::read_section(&mut file, header.section(SectionId::Keys)?)?, header.load_factor)?;
+        let values = FMValues {
+            data: format::read_section(&mut file, header.section(SectionId::Values)?)?,
+            layout: header.params.layout,
+        };
+        if keys.iter().any(|(_, (_, end))| end > values.data.len()) {
+            return Err(FormatError::InvalidSection(SectionId::Values).into());
         }
+        let catalog = ReferenceCatalog::from_bytes(&format::read_section::<u8>(&mut file, header.section(SectionId::Catalog)?)?)?;
+
+        Ok(FlexmapHash { keys, values, seeding: header.params.seeding, catalog })
     }
 
     // pub unsafe fn load(file: &mut File) -> Self {
@@ -157,6 +382,107 @@ FlexmapHash<C, F, HEADER_THRESHOLD>
 }
 
 
+impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize>
+FlexmapHash<C, F, HEADER_THRESHOLD>
+{
+    /// All cores with their values, in table order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, VRange<'_>)> + Send + '_ {
+        self.keys.iter().map(|(kmer, range)| (kmer, self.values.get_range(range)))
+    }
+
+    /// The same index with a direct key table. Fails if C is above MAX_DIRECT_C
+    /// or a core has more occurrences than a direct table can hold.
+    pub fn to_direct<const CELLS_PER_BODY: u64>(&self) -> Result<Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>, FlexmapError> {
+        if C > MAX_DIRECT_C {
+            return Err(FlexmapError::overflow("core length of a direct table", C as u64, MAX_DIRECT_C as u64));
+        }
+        let mut keys = FMKeys::<C, CELLS_PER_BODY>::new();
+        for (kmer, (start, end)) in self.keys.iter() {
+            let occurrences = BlockLayout::for_size::<HEADER_THRESHOLD>(end - start).positions;
+            let max = FMKeys::<C, CELLS_PER_BODY>::MAX_KEY_VALUESSIZE;
+            if occurrences > max {
+                return Err(FlexmapError::overflow("occurrences of a core in a direct table", occurrences as u64, max as u64));
+            }
+            keys.set_kmer_cell(kmer, occurrences as u16);
+        }
+        keys.build::<HEADER_THRESHOLD>(usize::MAX, RepeatPolicy::Drop);
+
+        let mut flexmap = Flexmap::with_layout(keys, self.values.layout);
+        for (kmer, (start, end)) in self.keys.iter() {
+            let (new_start, new_end) = flexmap.keys.vrange(kmer).expect("Converted cores have values");
+            assert_eq!(new_end - new_start, end - start);
+            flexmap.values.data[new_start..new_end].clone_from_slice(&self.values.data[start..end]);
+        }
+        flexmap.seeding = self.seeding;
+        flexmap.catalog = self.catalog.clone();
+        Ok(flexmap)
+    }
+
+    /// Seeds of read, selected with the scheme and parameters the index was
+    /// built with. K, S and L have to match the build.
+    pub fn seeds<'a, const K: usize, const S: usize, const L: usize>(&self, read: &'a [u8]) -> Result<impl Iterator<Item = ReadSeed> + 'a, FlexmapError> {
+        seeds::read_seeds::<K, C, F, S, L>(&self.seeding, read)
+    }
+
+    /// Seeds of read that occur in the index, with their values.
+    pub fn query_read<'a, const K: usize, const S: usize, const L: usize>(&'a self, read: &'a [u8]) -> Result<impl Iterator<Item = (ReadSeed, VRange<'a>)> + 'a, FlexmapError> {
+        Ok(self.seeds::<K, S, L>(read)?.filter_map(|seed| Some((seed, self.get_vrange(seed.core)?))))
+    }
+
+    /// Splits iter into at most chunks iterators over disjoint parts of the table,
+    /// e.g. to walk the index on several threads.
+    pub fn chunks(&self, chunks: usize) -> impl Iterator<Item = impl Iterator<Item = (u64, VRange<'_>)> + Send + '_> + '_ {
+        chunk_ranges(self.keys.data.len(), chunks).map(move |entries| {
+            FMKeysHash::iter_in(&self.keys.data[entries])
+                .map(move |(kmer, range)| (kmer, self.values.get_range(range)))
+        })
+    }
+}
+
+impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize> FlexIndex for
+    Flexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>
+{
+    fn index_params(&self) -> IndexParams {
+        Self::params(self.seeding, self.values.layout)
+    }
+
+    fn catalog(&self) -> &ReferenceCatalog {
+        &self.catalog
+    }
+
+    fn iter_vranges(&self) -> Box<dyn Iterator<Item = (u64, VRange<'_>)> + Send + '_> {
+        Box::new(self.iter())
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of_val(&self.keys.data[..]) + mem::size_of_val(&self.keys.overflow[..]) + mem::size_of_val(&self.values.data[..])
+    }
+}
+
+impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize> FlexIndex for
+    FlexmapHash<C, F, HEADER_THRESHOLD>
+{
+    fn index_params(&self) -> IndexParams {
+        Self::params(self.seeding, self.values.layout)
+    }
+
+    fn catalog(&self) -> &ReferenceCatalog {
+        &self.catalog
+    }
+
+    fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn iter_vranges(&self) -> Box<dyn Iterator<Item = (u64, VRange<'_>)> + Send + '_> {
+        Box::new(self.iter())
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of_val(&self.keys.data[..]) + mem::size_of_val(&self.values.data[..])
+    }
+}
+
 impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize> VRangeGetter for 
 FlexmapHash<C, F, HEADER_THRESHOLD> {
     /// Gets the VRange for a given k-mer (represented as u64). A VRange has an optional header section and a value section. 
@@ -164,7 +490,108 @@ FlexmapHash<C, F, HEADER_THRESHOLD> {
     /// header contains additional information about the flanking regions of the k-mer (parameter F). Returns None if 
     /// No such key is stored in the flexmap.
     fn get_vrange(&self, canonical_kmer: u64) -> Option<VRange> {
-        let range = self.keys.get(canonical_kmer as u32)?;
+        let range = self.keys.get(canonical_kmer)?;
         Some(self.values.get_range((range.0, range.0 + range.1)))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::keys::RepeatPolicy;
+
+    use super::*;
+
+    fn positions(vrange: &VRange) -> Vec<u64> {
+        vrange.positions.iter().map(|cell| cell.0).collect()
+    }
+
+    /// A direct and a hash index with the same cores and values.
+    fn small_indexes() -> (Flexmap<3, 8, 8, 2>, FlexmapHash<3, 8, 2>) {
+        let counts = [(5, 1), (6, 3), (40, 2), (63, 4)];
+        let mut keys = FMKeys::<3, 8>::new();
+        for (kmer, count) in counts {
+            keys.set_kmer_cell(kmer, count);
+        }
+        keys.build::<2>(100, RepeatPolicy::Drop);
+        let mut flexmap = Flexmap::<3, 8, 8, 2>::new(keys);
+        for (idx, cell) in flexmap.values.data.iter_mut().enumerate() {
+            cell.set_raw(idx as u64 + 1);
+        }
+
+        let mut hash_keys = FMKeysHash::with_capacity(4);
+        for (kmer, _) in counts {
+            let (start, end) = flexmap.keys.vrange(kmer).unwrap();
+            hash_keys.insert(kmer, start as u64, (end - start) as u32).unwrap();
+        }
+        let mut hash = FlexmapHash::<3, 8, 2>::new(hash_keys);
+        hash.values.data.clone_from(&flexmap.values.data);
+        (flexmap, hash)
+    }
+
+    #[test]
+    fn test_iterate_keys() {
+        let (flexmap, hash) = small_indexes();
+
+        let all: Vec<(u64, Vec<u64>)> = flexmap.iter().map(|(kmer, vrange)| (kmer, positions(&vrange))).collect();
+        assert_eq!(all.iter().map(|(kmer, _)| *kmer).collect::<Vec<_>>(), vec![5, 6, 40, 63]);
+        assert_eq!(all[1].1.len(), 3);
+
+        let mut hashed: Vec<(u64, Vec<u64>)> = hash.iter().map(|(kmer, vrange)| (kmer, positions(&vrange))).collect();
+        hashed.sort();
+        assert_eq!(hashed, all);
+
+        // Chunks cover every key exactly once, also when walked in parallel.
+        let chunked: Vec<(u64, Vec<u64>)> = flexmap.chunks(3).flatten().map(|(kmer, vrange)| (kmer, positions(&vrange))).collect();
+        assert_eq!(chunked, all);
+        let counts: Vec<usize> = thread::scope(|scope| {
+            let workers: Vec<_> = hash.chunks(3).map(|chunk| scope.spawn(move || chunk.count())).collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        assert!(counts.len() <= 3);
+        assert_eq!(counts.iter().sum::<usize>(), 4);
+    }
+
+    /// Summary of an index that only uses the FlexIndex trait.
+    fn summary<I: FlexIndex + ?Sized>(index: &I) -> (u32, usize, usize, Vec<Option<usize>>) {
+        let occurrences = index.iter_vranges().map(|(_, vrange)| vrange.positions.len()).sum();
+        let lookups = index.get_vranges(&[6, 7, 63]).iter().map(|vrange| vrange.as_ref().map(|vrange| vrange.positions.len())).collect();
+        (index.index_params().c, index.key_count(), occurrences, lookups)
+    }
+
+    #[test]
+    fn test_flex_index() {
+        let (flexmap, hash) = small_indexes();
+        let expected = (3, 4, 10, vec![Some(3), None, Some(4)]);
+        assert_eq!(summary(&flexmap), expected);
+        assert_eq!(summary(&hash), expected);
+
+        let indexes: [&dyn FlexIndex; 2] = [&flexmap, &hash];
+        for index in indexes {
+            assert_eq!(summary(index), expected);
+            assert_eq!(index.index_params().header_threshold, 2);
+            assert!(index.catalog().is_empty());
+        }
+        assert_eq!(flexmap.index_params().kind, IndexKind::Direct);
+        assert_eq!(hash.index_params().kind, IndexKind::Hash);
+        assert_eq!(hash.memory_usage(), hash.keys.capacity() * 24 + 14 * 8);
+    }
+
+    #[test]
+    fn test_verify() {
+        let (mut flexmap, mut hash) = small_indexes();
+        assert!(flexmap.verify().is_ok());
+        assert!(hash.verify().is_ok());
+
+        let (_, end) = flexmap.keys.vrange(6).unwrap();
+        flexmap.values.data[end - 1].set_raw(0);
+        hash.values.data[end - 1].set_raw(0);
+        for index in [&flexmap as &dyn FlexIndex, &hash] {
+            match index.verify() {
+                Err(FlexmapError::IncompleteBlock { core, filled, capacity }) => assert_eq!((core, filled, capacity), (6, 2, 3)),
+                other => panic!("expected an incomplete block, got {:?}", other.err()),
+            }
+        }
+    }
+}
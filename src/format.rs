@@ -0,0 +1,453 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::{mem, slice};
+
+use crate::keys::{KCell, KHashEntry};
+use crate::values::{VCell, ValueLayout};
+
+/// Index container
+///
+/// Every index file starts with a fixed size header followed by a number of
+/// raw sections (keys, values, ...). The header records the parameters the
+/// index was built with, so that loading an index into a type with different
+/// const generics fails with a typed error instead of reinterpreting memory.
+///
+///  0        ┌───────────────────────────┐
+///           │ magic "FLEXMAP\0"         │
+///           │ version, endianness, kind │
+///           │ C, F, CELLS_PER_BODY, ... │
+///           │ section table             │
+///  HEADER   ├───────────────────────────┤
+///  _SIZE    │ section 0 (64 byte align) │
+///           ├───────────────────────────┤
+///           │ section 1 (64 byte align) │
+///           └───────────────────────────┘
+///
+/// Sections are written in native byte order, the endianness marker is used
+/// to detect files that were written on a machine with a different one.
+
+pub const MAGIC: [u8; 8] = *b"FLEXMAP\0";
+pub const FORMAT_VERSION: u32 = 4;
+pub const ENDIANNESS_MARKER: u32 = 0x01020304;
+pub const HEADER_SIZE: usize = 512;
+pub const SECTION_ALIGNMENT: u64 = 64;
+pub const MAX_SECTIONS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum IndexKind {
+    Direct = 1,
+    Hash = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SectionId {
+    Keys = 1,
+    Values = 2,
+    Catalog = 3,
+    /// Key offsets of the blocks of a direct index that overflow a KCell.
+    Overflow = 4,
+}
+
+/// Parameters of the scheme used to select the seeds of an index, see
+/// seeds::SeedScheme. scheme is 0 for indexes that were not built from sequences.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Savefile, bincode::Encode, bincode::Decode, ser_raw::Serialize)]
+#[repr(C)]
+pub struct SeedParams {
+    pub scheme: u32,
+    pub k: u32,
+    pub s: u32,
+    pub l: u32,
+    /// Position of the smallest s-mer of open syncmers.
+    pub offset: u32,
+    /// Number of consecutive k-mers a minimizer is chosen from.
+    pub window: u32,
+}
+
+/// Everything that determines how the memory of an index has to be interpreted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IndexParams {
+    pub kind: IndexKind,
+    pub c: u32,
+    pub f: u32,
+    pub cells_per_body: u64,
+    pub header_threshold: u32,
+    pub layout: ValueLayout,
+    pub seeding: SeedParams,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Section {
+    pub id: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexHeader {
+    pub version: u32,
+    pub params: IndexParams,
+    pub load_factor: f64,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    EndiannessMismatch,
+    UnknownKind(u32),
+    UnknownSeedScheme(u32),
+    ParameterMismatch { name: &'static str, expected: u64, found: u64 },
+    MissingSection(SectionId),
+    InvalidSection(SectionId),
+    InvalidLayout,
+    Truncated,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "I/O error: {}", e),
+            FormatError::BadMagic => write!(f, "not a flexmap index (bad magic number)"),
+            FormatError::UnsupportedVersion(v) => write!(f, "unsupported index format version {} (expected {})", v, FORMAT_VERSION),
+            FormatError::EndiannessMismatch => write!(f, "index was written on a machine with different endianness"),
+            FormatError::UnknownKind(k) => write!(f, "unknown index kind {}", k),
+            FormatError::UnknownSeedScheme(scheme) => write!(f, "unknown seed scheme {}", scheme),
+            FormatError::ParameterMismatch { name, expected, found } => {
+                write!(f, "index parameter {} mismatch: expected {}, found {}", name, expected, found)
+            }
+            FormatError::MissingSection(id) => write!(f, "index is missing section {:?}", id),
+            FormatError::InvalidSection(id) => write!(f, "index section {:?} cannot be decoded", id),
+            FormatError::InvalidLayout => write!(f, "index has an invalid value layout"),
+            FormatError::Truncated => write!(f, "index file is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl From<std::io::Error> for FormatError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => FormatError::Truncated,
+            _ => FormatError::Io(e),
+        }
+    }
+}
+
+/// Types that can be dumped to and restored from a section byte for byte.
+/// Every bit pattern, including all zeros, has to be a valid value.
+pub unsafe trait PlainCell: Sized {}
+
+unsafe impl PlainCell for KCell {}
+unsafe impl PlainCell for VCell {}
+unsafe impl PlainCell for KHashEntry {}
+unsafe impl PlainCell for u8 {}
+unsafe impl PlainCell for u64 {}
+
+pub fn as_bytes<T: PlainCell>(data: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * mem::size_of::<T>()) }
+}
+
+const fn align_up(offset: u64) -> u64 {
+    (offset + SECTION_ALIGNMENT - 1) / SECTION_ALIGNMENT * SECTION_ALIGNMENT
+}
+
+struct HeaderWriter {
+    buffer: [u8; HEADER_SIZE],
+    pos: usize,
+}
+
+impl HeaderWriter {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buffer[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+    fn u32(&mut self, value: u32) { self.put(&value.to_ne_bytes()) }
+    fn u64(&mut self, value: u64) { self.put(&value.to_ne_bytes()) }
+}
+
+struct HeaderReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.buffer[self.pos..self.pos + N].try_into().expect("Header field");
+        self.pos += N;
+        bytes
+    }
+    fn u32(&mut self) -> u32 { u32::from_ne_bytes(self.take()) }
+    fn u64(&mut self) -> u64 { u64::from_ne_bytes(self.take()) }
+}
+
+impl IndexHeader {
+    pub fn new(params: IndexParams, load_factor: f64) -> Self {
+        IndexHeader { version: FORMAT_VERSION, params, load_factor, sections: Vec::new() }
+    }
+
+    pub fn section(&self, id: SectionId) -> Result<Section, FormatError> {
+        self.sections.iter()
+            .find(|section| section.id == id as u32)
+            .copied()
+            .ok_or(FormatError::MissingSection(id))
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        assert!(self.sections.len() <= MAX_SECTIONS);
+        let mut w = HeaderWriter { buffer: [0u8; HEADER_SIZE], pos: 0 };
+        w.put(&MAGIC);
+        w.u32(self.version);
+        w.u32(ENDIANNESS_MARKER);
+        w.u32(self.params.kind as u32);
+        w.u32(self.params.c);
+        w.u32(self.params.f);
+        w.u32(self.params.header_threshold);
+        w.u64(self.params.cells_per_body);
+        w.u32(self.params.layout.val_bits);
+        w.u32(self.params.layout.pos_bits);
+        w.u32(self.params.seeding.scheme);
+        w.u32(self.params.seeding.k);
+        w.u32(self.params.seeding.s);
+        w.u32(self.params.seeding.l);
+        w.u32(self.params.seeding.offset);
+        w.u32(self.params.seeding.window);
+        w.u32(self.sections.len() as u32);
+        w.u64(self.load_factor.to_bits());
+        for section in &self.sections {
+            w.u32(section.id);
+            w.u32(0);
+            w.u64(section.offset);
+            w.u64(section.len);
+        }
+        w.buffer
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self, FormatError> {
+        if buffer.len() < HEADER_SIZE { return Err(FormatError::Truncated) };
+        let mut r = HeaderReader { buffer, pos: 0 };
+        if r.take::<8>() != MAGIC { return Err(FormatError::BadMagic) };
+        let version = r.u32();
+        let endianness = r.u32();
+        if endianness != ENDIANNESS_MARKER {
+            return Err(if endianness.swap_bytes() == ENDIANNESS_MARKER {
+                FormatError::EndiannessMismatch
+            } else {
+                FormatError::BadMagic
+            });
+        }
+        if version != FORMAT_VERSION { return Err(FormatError::UnsupportedVersion(version)) };
+
+        let kind = match r.u32() {
+            1 => IndexKind::Direct,
+            2 => IndexKind::Hash,
+            other => return Err(FormatError::UnknownKind(other)),
+        };
+        let c = r.u32();
+        let f = r.u32();
+        let header_threshold = r.u32();
+        let cells_per_body = r.u64();
+        let layout = ValueLayout::new(r.u32(), r.u32()).map_err(|_| FormatError::InvalidLayout)?;
+        let seeding = SeedParams { scheme: r.u32(), k: r.u32(), s: r.u32(), l: r.u32(), offset: r.u32(), window: r.u32() };
+        let section_count = r.u32() as usize;
+        let load_factor = f64::from_bits(r.u64());
+        if section_count > MAX_SECTIONS { return Err(FormatError::Truncated) };
+
+        let mut sections = Vec::with_capacity(section_count);
+        for _ in 0..section_count {
+            let id = r.u32();
+            let _ = r.u32();
+            sections.push(Section { id, offset: r.u64(), len: r.u64() });
+        }
+
+        Ok(IndexHeader {
+            version,
+            params: IndexParams { kind, c, f, cells_per_body, header_threshold, layout, seeding },
+            load_factor,
+            sections,
+        })
+    }
+
+    /// Checks that the recorded parameters match the ones of the type the index is
+    /// loaded into. Seed parameters and value layout are properties of the
+    /// index itself and are not compared.
+    pub fn check(&self, expected: &IndexParams) -> Result<(), FormatError> {
+        let found = &self.params;
+        let mismatch = |name, expected: u64, found: u64| {
+            if expected != found {
+                Err(FormatError::ParameterMismatch { name, expected, found })
+            } else {
+                Ok(())
+            }
+        };
+        mismatch("kind", expected.kind as u64, found.kind as u64)?;
+        mismatch("C", expected.c as u64, found.c as u64)?;
+        mismatch("F", expected.f as u64, found.f as u64)?;
+        mismatch("CELLS_PER_BODY", expected.cells_per_body, found.cells_per_body)?;
+        mismatch("HEADER_THRESHOLD", expected.header_threshold as u64, found.header_threshold as u64)?;
+        Ok(())
+    }
+}
+
+/// Writes header and sections. Section offsets are assigned in the given order,
+/// each one aligned to SECTION_ALIGNMENT.
+pub fn write_index<W: Write>(
+    writer: &mut W,
+    mut header: IndexHeader,
+    sections: &[(SectionId, &[u8])],
+) -> Result<(), FormatError> {
+    let mut offset = HEADER_SIZE as u64;
+    header.sections.clear();
+    for (id, bytes) in sections {
+        offset = align_up(offset);
+        header.sections.push(Section { id: *id as u32, offset, len: bytes.len() as u64 });
+        offset += bytes.len() as u64;
+    }
+
+    writer.write_all(&header.to_bytes())?;
+    let mut written = HEADER_SIZE as u64;
+    let padding = [0u8; SECTION_ALIGNMENT as usize];
+    for (section, (_, bytes)) in header.sections.iter().zip(sections) {
+        writer.write_all(&padding[..(section.offset - written) as usize])?;
+        writer.write_all(bytes)?;
+        written = section.offset + section.len;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_header<R: Read>(reader: &mut R) -> Result<IndexHeader, FormatError> {
+    let mut buffer = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut buffer)?;
+    IndexHeader::from_bytes(&buffer)
+}
+
+pub fn read_section<T: PlainCell>(file: &mut File, section: Section) -> Result<Vec<T>, FormatError> {
+    let size = mem::size_of::<T>() as u64;
+    if section.len % size != 0 { return Err(FormatError::Truncated) };
+    let count = (section.len / size) as usize;
+
+    // Check the length before allocating, a corrupt header could ask for any size.
+    let end = section.offset.checked_add(section.len).ok_or(FormatError::Truncated)?;
+    if end > file.metadata()?.len() { return Err(FormatError::Truncated) };
+
+    file.seek(SeekFrom::Start(section.offset))?;
+    let mut data: Vec<T> = Vec::with_capacity(count);
+    // All zeros is a valid PlainCell, so the buffer is initialised before it is read into.
+    let bytes: &mut [u8] = unsafe {
+        data.as_mut_ptr().write_bytes(0, count);
+        data.set_len(count);
+        slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, section.len as usize)
+    };
+    file.read_exact(bytes)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::catalog::ReferenceEntry;
+    use crate::error::FlexmapError;
+    use crate::flexmap::Flexmap;
+    use crate::keys::{FMKeys, RepeatPolicy};
+
+    use super::*;
+
+    fn small_flexmap() -> Flexmap<3, 8, 8, 2> {
+        let mut keys = FMKeys::<3, 8>::new();
+        keys.get_kmer_cell_mut_ref(5).increment();
+        keys.get_kmer_cell_mut_ref(6).increment();
+        keys.get_kmer_cell_mut_ref(6).increment();
+        keys.get_kmer_cell_mut_ref(6).increment();
+        keys.build::<2>(100, RepeatPolicy::Drop);
+        let mut flexmap = Flexmap::<3, 8, 8, 2>::new(keys);
+        for (idx, cell) in flexmap.values.data.iter_mut().enumerate() {
+            cell.set_raw(idx as u64 + 1);
+        }
+        let entry = ReferenceEntry { name: "chr1".into(), length: 42, description: "test".into(), group: Some(7), source: None };
+        flexmap.catalog.insert(entry).unwrap();
+        flexmap
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut header = IndexHeader::new(Flexmap::<3, 8, 8, 2>::params(SeedParams { scheme: 4, k: 11, s: 2, l: 2, offset: 0, window: 10 }, ValueLayout::new(20, 40).unwrap()), 0.0);
+        header.sections.push(Section { id: SectionId::Keys as u32, offset: 256, len: 10 });
+        let parsed = IndexHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(parsed, header);
+    }
+
+    #[test]
+    fn test_flexmap_save_load() {
+        let flexmap = small_flexmap();
+        let path = std::env::temp_dir().join(format!("flexmap_test_flexmap_save_load_{}.idx", std::process::id()));
+        flexmap.save(&path).unwrap();
+
+        let loaded = Flexmap::<3, 8, 8, 2>::load(&path).unwrap();
+        assert!(loaded.keys.data.iter().map(|c| c.0).eq(flexmap.keys.data.iter().map(|c| c.0)));
+        assert_eq!(loaded.keys.overflow, flexmap.keys.overflow);
+        assert!(loaded.values.data.iter().map(|c| c.0).eq(flexmap.values.data.iter().map(|c| c.0)));
+        assert_eq!(loaded.catalog, flexmap.catalog);
+
+        match Flexmap::<3, 8, 8, 3>::load(&path) {
+            Err(FlexmapError::FormatMismatch(FormatError::ParameterMismatch { name, expected, found })) => {
+                assert_eq!((name, expected, found), ("HEADER_THRESHOLD", 3, 2));
+            }
+            _ => panic!("Loading with a different HEADER_THRESHOLD must fail"),
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_value_layout_save_load() {
+        let mut flexmap = small_flexmap();
+        let layout = ValueLayout::new(40, 20).unwrap();
+        flexmap.values.layout = layout;
+        let packed = layout.pack(1 << 30, 12345).unwrap();
+        flexmap.values.data[0].set_raw(packed);
+        let path = std::env::temp_dir().join(format!("flexmap_test_value_layout_{}.idx", std::process::id()));
+        flexmap.save(&path).unwrap();
+
+        let loaded = Flexmap::<3, 8, 8, 2>::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.values.layout, layout);
+        assert_eq!(loaded.values.layout.unpack(loaded.values.data[0].payload()), (1 << 30, 12345));
+    }
+
+    #[test]
+    fn test_corrupt_sections() {
+        let mut flexmap = small_flexmap();
+        let path = std::env::temp_dir().join(format!("flexmap_test_corrupt_sections_{}.idx", std::process::id()));
+        let rewrite_header = |edit: &dyn Fn(&mut IndexHeader)| {
+            let mut bytes = std::fs::read(&path).unwrap();
+            let mut header = IndexHeader::from_bytes(&bytes).unwrap();
+            edit(&mut header);
+            bytes[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+            std::fs::write(&path, bytes).unwrap();
+        };
+
+        // A section length beyond the end of the file fails before anything is allocated.
+        flexmap.save(&path).unwrap();
+        rewrite_header(&|header| {
+            let values = header.sections.iter_mut().find(|section| section.id == SectionId::Values as u32).unwrap();
+            values.len = 1 << 60;
+        });
+        assert!(matches!(Flexmap::<3, 8, 8, 2>::load(&path), Err(FlexmapError::FormatMismatch(FormatError::Truncated))));
+
+        // Values that do not match the keys.
+        flexmap.values.data.pop();
+        flexmap.save(&path).unwrap();
+        assert!(matches!(Flexmap::<3, 8, 8, 2>::load(&path), Err(FlexmapError::FormatMismatch(FormatError::InvalidSection(SectionId::Values)))));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let buffer = [0u8; HEADER_SIZE];
+        assert!(matches!(IndexHeader::from_bytes(&buffer), Err(FormatError::BadMagic)));
+    }
+}
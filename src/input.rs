@@ -0,0 +1,122 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::error::FlexmapError;
+
+/// File name endings of FASTA files picked up from a directory, each also with .gz.
+pub const FASTA_EXTENSIONS: [&str; 6] = ["fa", "fasta", "fna", "ffn", "frn", "fas"];
+
+/// The reference files a build reads. References get their ids in the order
+/// of the files returned by paths, and within a file in record order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildInput {
+    /// Files in the given order.
+    Files(Vec<PathBuf>),
+    /// All FASTA files below a directory (see FASTA_EXTENSIONS), sorted by path.
+    Directory(PathBuf),
+    /// Text file with one path per line. Empty lines and lines starting with #
+    /// are ignored, relative paths are relative to the directory of the manifest.
+    Manifest(PathBuf),
+}
+
+impl BuildInput {
+    pub fn file(path: impl AsRef<Path>) -> Self {
+        BuildInput::Files(vec![path.as_ref().to_path_buf()])
+    }
+
+    /// Resolves the input to the list of files in build order.
+    pub fn paths(&self) -> Result<Vec<PathBuf>, FlexmapError> {
+        match self {
+            BuildInput::Files(paths) => Ok(paths.clone()),
+            BuildInput::Directory(dir) => {
+                let mut paths = Vec::new();
+                collect_fasta(dir, &mut paths)?;
+                if paths.is_empty() {
+                    return Err(FlexmapError::io(dir, io::Error::new(io::ErrorKind::NotFound, "no FASTA files in directory")));
+                }
+                paths.sort();
+                Ok(paths)
+            }
+            BuildInput::Manifest(manifest) => {
+                let file = File::open(manifest).map_err(|e| FlexmapError::io(manifest, e))?;
+                let base = manifest.parent().unwrap_or(Path::new(""));
+                let mut paths = Vec::new();
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(|e| FlexmapError::io(manifest, e))?;
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') { continue };
+                    paths.push(base.join(line));
+                }
+                Ok(paths)
+            }
+        }
+    }
+}
+
+pub fn is_fasta(path: &Path) -> bool {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    match name.rsplit_once('.') {
+        Some((_, extension)) => FASTA_EXTENSIONS.contains(&extension),
+        None => false,
+    }
+}
+
+fn collect_fasta(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), FlexmapError> {
+    for entry in fs::read_dir(dir).map_err(|e| FlexmapError::io(dir, e))? {
+        let path = entry.map_err(|e| FlexmapError::io(dir, e))?.path();
+        if path.is_dir() {
+            collect_fasta(&path, paths)?;
+        } else if is_fasta(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Opens a file for reading, gzipped files (recognized by their magic bytes) are decompressed.
+pub fn open_reader(path: &Path) -> Result<Box<dyn Read + Send>, FlexmapError> {
+    let file = File::open(path).map_err(|e| FlexmapError::io(path, e))?;
+    let mut reader = BufReader::new(file);
+    let gzipped = reader.fill_buf().map_err(|e| FlexmapError::io(path, e))?.starts_with(&[0x1f, 0x8b]);
+    if gzipped {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn test_paths_and_readers() {
+        let dir = std::env::temp_dir().join(format!("flexmap_input_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("b.fna"), ">b\nACGT\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a reference").unwrap();
+        let mut gz = GzEncoder::new(File::create(dir.join("sub/a.fa.gz")).unwrap(), Compression::default());
+        gz.write_all(b">a\nTTTT\n").unwrap();
+        gz.finish().unwrap();
+        fs::write(dir.join("manifest.txt"), "# references\nsub/a.fa.gz\n\nb.fna\n").unwrap();
+
+        let listed = BuildInput::Directory(dir.clone()).paths().unwrap();
+        assert_eq!(listed, vec![dir.join("b.fna"), dir.join("sub/a.fa.gz")]);
+        let manifest = BuildInput::Manifest(dir.join("manifest.txt")).paths().unwrap();
+        assert_eq!(manifest, vec![dir.join("sub/a.fa.gz"), dir.join("b.fna")]);
+
+        let mut content = String::new();
+        open_reader(&dir.join("sub/a.fa.gz")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, ">a\nTTTT\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
 
-use std::{array, borrow::Borrow, cell::Cell, collections::HashMap, default, error::Error, fs::{self, File}, hash::{BuildHasher, Hash}, intrinsics::size_of, io::{Read, Write}, mem::{self, transmute}, num::Wrapping, process::exit};
+use std::{array, borrow::Borrow, cell::Cell, collections::HashMap, default, error::Error, fs, hash::{BuildHasher, Hash}, io::Write, mem::{self, transmute}, num::Wrapping, process::exit};
+use std::{cmp::{max, min}, ops::Range, slice, sync::atomic::{AtomicU16, Ordering}};
 use bincode::{Decode, Encode};
 use fxhash::FxBuildHasher;
 use savefile::{Deserialize, Serialize, WithSchema};
 use bioreader::utils::time_noerr;
 use kmerrs::consecutive::kmer::Kmer;
 
+use crate::error::FlexmapError;
+use crate::layout::BlockLayout;
+
 #[derive(Debug)]
 
 // ctrl_block_keys_index
@@ -65,6 +69,9 @@ pub const fn table_size<const C: usize, const CELLS_PER_BODY: u64>() -> usize {
 #[repr(C)]
 pub struct FMKeys<const C: usize, const CELLS_PER_BODY: u64> { //where [(); table_size::<C,CELLS_PER_BODY>()]: 
     pub data: Vec<KCell>,
+    /// Value offsets of the keys of blocks whose offsets do not fit into a KCell,
+    /// CELLS_PER_BODY absolute offsets per block (see OVERFLOW_FLAG).
+    pub overflow: Vec<u64>,
     // data: [KCell; ],
 }
 
@@ -79,8 +86,12 @@ impl<const C: usize, const CELLS_PER_BODY: u64>
     const KEY_TO_CTRL_BLOCK_SHIFT: u64 = CELLS_PER_BODY.ilog2() as u64; // BITSHIFT to get the ctrl block number for key
     const KEY_BLOCK_MASK: u64 = CELLS_PER_BODY - 1;
     const CELLS_PER_HEAD: u64 = 4; // Given this implementation, u16 is fixed as cell type. A head is a u64 so takes 4 cells
-    const MAX_BLOCK_VALUESSIZE: usize = 2usize.pow(size_of::<KCell>() as u32 * 8);
-    const MAX_KEY_VALUESSIZE: usize = Self::MAX_BLOCK_VALUESSIZE / CELLS_PER_BODY as usize;
+    const MAX_BLOCK_OFFSET: u64 = u16::MAX as u64;
+    /// Counts saturate at u16::MAX, so a key with that count may occur more often.
+    pub const MAX_KEY_VALUESSIZE: usize = u16::MAX as usize - 1;
+    /// Set in the control header of a block whose key offsets exceed MAX_BLOCK_OFFSET.
+    /// The remaining bits are the index of the block's offsets in overflow.
+    pub const OVERFLOW_FLAG: u64 = 1 << 63;
 
     pub const fn table_size() -> u64 { 
         let number_of_keys = usize::pow(2, (C*2) as u32) as u64;
@@ -117,15 +128,33 @@ impl<const C: usize, const CELLS_PER_BODY: u64>
         self.data[Self::kmer_to_index(canonical_kmer)].set(value);
     }
 
+    /// The key cells as atomics, so that several threads can count into the same table.
+    pub fn atomic_cells(&mut self) -> &[AtomicU16] {
+        // KCell is a repr(C) u16 and AtomicU16 has the same size and alignment as u16.
+        unsafe { slice::from_raw_parts(self.data.as_mut_ptr() as *const AtomicU16, self.data.len()) }
+    }
+
+    /// Counts one occurrence of canonical_kmer in cells. Saturates instead of
+    /// wrapping to 0, such a key is dropped by build anyway.
+    pub fn increment_atomic(cells: &[AtomicU16], canonical_kmer: u64) {
+        let _ = cells[Self::kmer_to_index(canonical_kmer)]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| count.checked_add(1));
+    }
+
     pub fn vrange(&self, canonical_kmer: u64) -> Option<(usize, usize)> {
-        let (block_index, key_index) = Self::kmer_to_indexes(canonical_kmer);
-        let ctrl_block_value = self.get_control_header_value(block_index as usize);
+        Self::vrange_in(&self.data, &self.overflow, canonical_kmer)
+    }
 
-        let value_start = ctrl_block_value as usize + self.data[key_index as usize].0 as usize;
-        let value_end: usize = if (canonical_kmer & Self::KEY_BLOCK_MASK) != Self::KEY_BLOCK_MASK {
-            ctrl_block_value as usize + self.data[key_index as usize + 1].0 as usize
+    /// Same as vrange but works on a borrowed key table, e.g. one that is memory mapped.
+    pub fn vrange_in(data: &[KCell], overflow: &[u64], canonical_kmer: u64) -> Option<(usize, usize)> {
+        let (block_index, _) = Self::kmer_to_indexes(canonical_kmer);
+        let key = canonical_kmer & Self::KEY_BLOCK_MASK;
+
+        let value_start = Self::key_start_in(data, overflow, block_index as usize, key);
+        let value_end: usize = if key != Self::KEY_BLOCK_MASK {
+            Self::key_start_in(data, overflow, block_index as usize, key + 1)
         } else {
-            self.get_control_header_value((block_index + Self::CELLS_PER_HEAD + CELLS_PER_BODY) as usize) as usize
+            Self::key_start_in(data, overflow, (block_index + Self::CELLS_PER_HEAD + CELLS_PER_BODY) as usize, 0)
         };
         assert!(value_start <= value_end);
 
@@ -136,6 +165,55 @@ impl<const C: usize, const CELLS_PER_BODY: u64>
         Some((value_start, value_end))
     }
 
+    /// Start of the values of the key-th key of the block at block_index.
+    fn key_start_in(data: &[KCell], overflow: &[u64], block_index: usize, key: u64) -> usize {
+        let ctrl_block_value = Self::control_header_value_in(data, block_index);
+        if ctrl_block_value & Self::OVERFLOW_FLAG != 0 {
+            return overflow[(ctrl_block_value & !Self::OVERFLOW_FLAG) as usize + key as usize] as usize
+        }
+        if key == 0 {
+            // The sentinel header at the end of the table has no keys.
+            return ctrl_block_value as usize
+        }
+        ctrl_block_value as usize + data[block_index + (Self::CELLS_PER_HEAD + key) as usize].0 as usize
+    }
+
+    /// Keys in kmers that have values, with their value range, in key order.
+    pub fn iter_range(&self, kmers: Range<u64>) -> impl Iterator<Item = (u64, (usize, usize))> + '_ {
+        Self::iter_in(&self.data, &self.overflow, kmers)
+    }
+
+    /// Same as iter_range but works on a borrowed key table.
+    pub fn iter_in<'a>(data: &'a [KCell], overflow: &'a [u64], kmers: Range<u64>) -> impl Iterator<Item = (u64, (usize, usize))> + 'a {
+        kmers.filter_map(move |kmer| Some((kmer, Self::vrange_in(data, overflow, kmer)?)))
+    }
+
+    /// All keys that have values, with their value range, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, (usize, usize))> + '_ {
+        self.iter_range(0..1 << (2 * C))
+    }
+
+    /// Number of blocks whose offsets are stored in overflow.
+    pub fn overflow_blocks(&self) -> usize {
+        self.overflow.len() / CELLS_PER_BODY as usize
+    }
+
+    /// Numbers the keys that have values densely, in key order.
+    pub fn key_slots(&self) -> Result<KeySlots, FlexmapError> {
+        let mut occupied = vec![0u64; (1usize << (2 * C)).div_ceil(64)];
+        for (kmer, _) in self.iter() {
+            occupied[(kmer / 64) as usize] |= 1 << (kmer % 64);
+        }
+        let mut base = Vec::with_capacity(occupied.len());
+        let mut len = 0u64;
+        for word in &occupied {
+            base.push(u32::try_from(len)
+                .map_err(|_| FlexmapError::overflow("keys with values", len, u32::MAX as u64))?);
+            len += word.count_ones() as u64;
+        }
+        Ok(KeySlots { occupied, base, len: len as usize })
+    }
+
 
     pub fn get_value(data: &[u16]) -> u64 {
         (data[0] as u64) | 
@@ -146,8 +224,15 @@ impl<const C: usize, const CELLS_PER_BODY: u64>
 
     // #[inline(always)]
     fn get_control_header_value(&self, index: usize) -> u64 {
+        Self::control_header_value_in(&self.data, index)
+    }
+
+    // #[inline(always)]
+    fn control_header_value_in(data: &[KCell], index: usize) -> u64 {
+        assert!(index + 3 < data.len());
+        // A control header is only 2-byte aligned (and may live in a mapped file)
         unsafe {
-            *self.data.as_ptr().add(index).cast::<u64>()
+            data.as_ptr().add(index).cast::<u64>().read_unaligned()
         }
     }
 
@@ -194,149 +279,180 @@ impl<const C: usize, const CELLS_PER_BODY: u64>
     pub fn new() -> FMKeys<C, CELLS_PER_BODY> {
         FMKeys {
             data: vec![KCell(0); Self::table_size().try_into().unwrap()],
+            overflow: Vec::new(),
         }
     }
 
     pub fn with_capacity(capacity: usize) -> FMKeys<C, CELLS_PER_BODY> {
         FMKeys {
             data: Vec::with_capacity(capacity),
+            overflow: Vec::new(),
         }
     }
 
-    pub const fn calc_header_size(size: usize) -> usize {
-        (size as u64 + 1 >> 1) as usize
-    }
-
     pub fn get_values_size(&self) -> usize {
-        let block_index = self.data.len() - Self::CELLS_PER_HEAD as usize;
-        self.get_control_header_value(block_index) as usize
+        Self::values_size_in(&self.data)
     }
 
-    pub fn save(&mut self, filename: &String) -> () {
-
-        let mut f = File::create(&filename).expect("no file found");
-        // Convert Vec<u16> to raw bytes
-        let bytes: &[u8] = unsafe {
-            // Get a raw pointer to the vector's data
-            let ptr = self.data.as_ptr();
-
-            // Calculate the length of the data in bytes
-            let len = self.data.len() * mem::size_of::<u16>();
-
-            // Create a slice of u8 from the raw pointer and length
-            std::slice::from_raw_parts(ptr as *const u8, len)
-        };
-        
-        f.write_all(bytes);
+    /// Same as get_values_size but works on a borrowed key table.
+    pub fn values_size_in(data: &[KCell]) -> usize {
+        let block_index = data.len() - Self::CELLS_PER_HEAD as usize;
+        Self::control_header_value_in(data, block_index) as usize
     }
 
-    pub fn load(filename: &String) -> FMKeys<C, CELLS_PER_BODY> {
-        let mut f = File::open(&filename).expect("no file found");
-        
-
-        // Determine the length of the file
-        let metadata = match f.metadata() {
-            Ok(metadata) => metadata,
-            Err(e) => {
-                panic!("Error getting file metadata: {}", e)
-            }
-        };
-
-        let file_size = metadata.len() as usize;
-
-        let mut keys = Self::with_capacity(file_size/2);
-
-        // Calculate the number of u16 elements to read
-        let num_u16_elements = file_size / mem::size_of::<KCell>();
+    /// Turns the counts into value offsets. Keys above max_range_size or
+    /// MAX_KEY_VALUESSIZE are handled according to policy and listed in the report.
+    /// Blocks whose offsets do not fit into a KCell are moved to the overflow table.
+    pub fn build<const HEADER_THRESHOLD: usize>(&mut self, max_range_size: usize, policy: RepeatPolicy) -> BuildReport {
+        let mut report = BuildReport::default();
+        self.overflow.clear();
 
+        let mut running_vindex = 0;
+        let mut block_offsets = Vec::with_capacity(CELLS_PER_BODY as usize);
 
-        // Use unsafe code to reinterpret vec_u16 as a Vec<u8>
-        let vec_u8: &mut [u8] = unsafe {
-            // Get a mutable reference to the entire vec_u16's buffer as u8
-            let ptr = keys.data.as_mut_ptr() as *mut u8;
-            std::slice::from_raw_parts_mut(ptr, num_u16_elements * mem::size_of::<u16>())
-        };
+        let size = u64::pow(2, C as u32*2);
 
-        // Read u8 data directly into vec_u8
-        match f.read_exact(vec_u8) {
-            Ok(_) => {
-                // At this point, vec_u16 contains the data read from the file
-                println!("Data read from file: success");
+        let mut set_keys = 0;
+        for block_start in (0..size).step_by(CELLS_PER_BODY as usize) {
+            let block_index = Self::kmer_to_ctrl_block_index(block_start);
+            let mut block_vindex = 0;
+            block_offsets.clear();
+            for ckmer in block_start..block_start + CELLS_PER_BODY {
+                let mut ckmer_count = self.get_kmer_cell(ckmer).0 as u64;
+
+                if ckmer_count > 0 { set_keys += 1 };
+                ckmer_count = policy.apply(ckmer, ckmer_count, max_range_size, Self::MAX_KEY_VALUESSIZE, &mut report);
+
+                block_offsets.push(block_vindex);
+                block_vindex += BlockLayout::for_occurrences::<HEADER_THRESHOLD>(ckmer_count as usize).size() as u64;
             }
-            Err(e) => {
-                panic!("Error reading from file: {}", e);
+
+            // Only the offsets have to fit, the end of the last key is the next header.
+            if block_offsets.last().is_some_and(|&offset| offset > Self::MAX_BLOCK_OFFSET) {
+                self.set_control_header_value(block_index, Self::OVERFLOW_FLAG | self.overflow.len() as u64);
+                for (key, &offset) in block_offsets.iter().enumerate() {
+                    self.set_kmer_cell(block_start + key as u64, 0);
+                    self.overflow.push(running_vindex + offset);
+                }
+            } else {
+                self.set_control_header_value(block_index, running_vindex);
+                for (key, &offset) in block_offsets.iter().enumerate() {
+                    self.set_kmer_cell(block_start + key as u64, offset as u16);
+                }
             }
+            running_vindex += block_vindex;
         }
-        keys
+        let block_index = self.data.len() - Self::CELLS_PER_HEAD as usize;
+        self.set_control_header_value(block_index, running_vindex);
+
+        assert!(self.get_control_header_value(block_index) == running_vindex);
+
+        eprintln!("Non null k-mers {}", set_keys);
 
+        eprintln!("Overflow blocks {}", self.overflow_blocks());
+        report
     }
 
-    pub fn build<const HEADER_THRESHOLD: usize>(&mut self, max_range_size: usize) {
-        let mut value_index = 0;
 
-        let mut block_index = usize::MAX;
-        let mut block_value = 0;
-        let mut running_vindex = 0;
-        let mut block_vindex = 0;
-        
-        let mut skip = 0;
+}  
 
-        let size = u64::pow(2, C as u32*2);
 
-        let mut set_keys = 0;
-        let last_block_index = 0;
-        for ckmer in 0..size {
-            let kmer = Kmer::<C>(ckmer);
-            let ckmer_block_index = Self::kmer_to_ctrl_block_index(ckmer);
-            if block_index != ckmer_block_index {
-                block_index = ckmer_block_index;
-                running_vindex += block_vindex;
-                self.set_control_header_value(block_index, running_vindex);
+/// What a build does with keys that occur more often than its max_range_size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RepeatPolicy {
+    /// The key is not indexed.
+    #[default]
+    Drop,
+    /// The key keeps cap of its occurrences, those with the smallest hashes of
+    /// their position, so the sample is the same for every build of the references.
+    Subsample { cap: usize },
+}
 
-                assert!(self.get_control_header_value(block_index) == running_vindex);
-                block_vindex = 0;
+impl RepeatPolicy {
+    /// Number of occurrences the block of key reserves. Keys with more than
+    /// max_range_size occurrences or more than the key table can hold (limit)
+    /// are handled according to the policy. Keys that lose occurrences are added to report.
+    pub fn apply(&self, key: u64, count: u64, max_range_size: usize, limit: usize, report: &mut BuildReport) -> u64 {
+        if count as usize <= max_range_size && count as usize <= limit {
+            return count
+        }
+        match *self {
+            RepeatPolicy::Drop => {
+                report.dropped.push((key, count));
+                0
             }
-            let mut ckmer_count = self.get_kmer_cell(ckmer).0 as u64;
-
-            if ckmer_count > 0 { set_keys += 1 };
-            if ckmer_count as usize > max_range_size || ckmer_count as usize > Self::MAX_KEY_VALUESSIZE {
-                // eprintln!("{} {}  (user_max_range: {} , data_structure_max_range {})", kmer.to_string().unwrap(), ckmer_count, max_range_size, Self::MAX_KEY_VALUESSIZE);
-                skip += 1;
-                ckmer_count = 0;
+            RepeatPolicy::Subsample { cap } => {
+                let kept = min(min(cap, limit) as u64, count);
+                if kept < count {
+                    report.subsampled.push((key, count));
+                }
+                kept
             }
-
-            // print!("Kmer {} {} ({}): {} -> ", ckmer, kmer.to_string().unwrap(), kmer.is_smallest_rc(), ckmer_count);
-            self.set_kmer_cell(ckmer, block_vindex as u16);
-            // println!("{} -> {}", block_vindex, running_vindex + block_vindex);
-            let key_vsize = ckmer_count + (((ckmer_count > HEADER_THRESHOLD as u64) as u64) * (Self::calc_header_size(ckmer_count as usize) as u64));
-            block_vindex += key_vsize;
         }
-        block_index = self.data.len() - Self::CELLS_PER_HEAD as usize;
-        running_vindex += block_vindex;
-        self.set_control_header_value(block_index, running_vindex);
+    }
+}
 
-        assert!(self.get_control_header_value(block_index) == running_vindex);
+/// Dense numbering of the keys of an FMKeys that have values, see FMKeys::key_slots.
+/// One bit per key tells whether it has values, the slot of a key is the
+/// number of such keys before its 64 bit word plus those before it in the word.
+pub struct KeySlots {
+    occupied: Vec<u64>,
+    base: Vec<u32>,
+    len: usize,
+}
 
-        eprintln!("Non null k-mers {}", set_keys);
+impl KeySlots {
+    /// Number of keys with values, slots are 0..len().
+    pub fn len(&self) -> usize {
+        self.len
+    }
 
-        eprintln!("Skipped {}", skip);
+    /// Slot of canonical_kmer, only meaningful for keys that have values.
+    pub fn slot(&self, canonical_kmer: u64) -> usize {
+        let word = (canonical_kmer / 64) as usize;
+        let before = self.occupied[word] & ((1 << (canonical_kmer % 64)) - 1);
+        self.base[word] as usize + before.count_ones() as usize
     }
+}
 
+/// Keys build did not index completely, as (key, number of occurrences).
+/// Counts above u16::MAX are reported as u16::MAX.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildReport {
+    /// Keys without any values.
+    pub dropped: Vec<(u64, u64)>,
+    /// Keys that keep only the occurrences with the smallest hashes, see RepeatPolicy::Subsample.
+    pub subsampled: Vec<(u64, u64)>,
+}
 
-}  
+impl BuildReport {
+    /// Writes one line per key: core sequence, occurrences, dropped or subsampled
+    /// (the occurrences with the smallest hashes were kept).
+    pub fn write_tsv<const C: usize>(&self, mut writer: impl Write) -> std::io::Result<()> {
+        for (action, keys) in [("dropped", &self.dropped), ("subsampled", &self.subsampled)] {
+            for &(key, count) in keys {
+                writeln!(writer, "{}\t{}\t{}", Kmer::<C>(key).to_string().unwrap_or_default(), count, action)?;
+            }
+        }
+        Ok(())
+    }
+}
 
 
 #[derive(Clone, Encode, Decode, Savefile)]
 #[repr(C)]
 pub struct KHashEntry {
-    pub key: u32,
-    pub range_len: u32,
+    /// The whole canonical core, so two cores can never share an entry.
+    pub key: u64,
     pub range_start: u64,
+    pub range_len: u32,
+    /// Entries are written to index files byte for byte, so they must not contain padding.
+    reserved: u32,
 }
 
 impl Default for KHashEntry {
     fn default() -> Self {
-        Self { key: 0, range_len: 0, range_start: 0 }
+        Self { key: 0, range_start: 0, range_len: 0, reserved: 0 }
     }
 }
 
@@ -344,14 +460,61 @@ impl Default for KHashEntry {
 #[repr(C)]
 pub struct FMKeysHash {
     pub data: Vec<KHashEntry>,
+    /// The table grows once more than this share of its cells is used.
     pub load_factor: f64,
+    len: usize,
 }
 
 impl FMKeysHash {
+    /// The block of a key, including its header, has to fit into range_len.
+    pub const MAX_KEY_VALUESSIZE: usize = u32::MAX as usize / 2;
+    pub const DEFAULT_LOAD_FACTOR: f64 = 0.6;
+
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             data: vec![KHashEntry::default(); capacity],
-            load_factor: 0.6,
+            load_factor: Self::DEFAULT_LOAD_FACTOR,
+            len: 0,
+        }
+    }
+
+    /// A table that holds keys entries without growing.
+    pub fn with_expected_keys(keys: usize) -> Self {
+        Self::with_capacity(Self::capacity_for(keys, Self::DEFAULT_LOAD_FACTOR).expect("Default load factor is valid"))
+    }
+
+    /// Wraps a table that was filled elsewhere, e.g. read from an index file.
+    pub fn from_data(data: Vec<KHashEntry>, load_factor: f64) -> Result<Self, FlexmapError> {
+        Self::check_load_factor(load_factor)?;
+        let len = data.iter().filter(|entry| !entry.is_empty()).count();
+        Ok(Self { data, load_factor, len })
+    }
+
+    /// Number of keys in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of cells in the table.
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    fn capacity_for(keys: usize, load_factor: f64) -> Result<usize, FlexmapError> {
+        Self::check_load_factor(load_factor)?;
+        Ok(((keys as f64 / load_factor).ceil() as usize).max(keys + 1))
+    }
+
+    /// A load factor of 1 or more never grows a full table, 0 or less never fits a key.
+    fn check_load_factor(load_factor: f64) -> Result<(), FlexmapError> {
+        if load_factor > 0.0 && load_factor < 1.0 {
+            Ok(())
+        } else {
+            Err(FlexmapError::InvalidLoadFactor(load_factor))
         }
     }
 }
@@ -374,109 +537,163 @@ impl FMKeysHash {
         return k.0;
     }
 
-    pub fn insert(&mut self, mut key: u32, mut range_start: u64, mut range_len: u32) -> Option<()> {
-        let mut index = Self::hash(key as u64) as usize % self.data.len();
+    /// Distance of the entry at index from the cell its key hashes to.
+    #[inline(always)]
+    fn probe_distance(data: &[KHashEntry], index: usize) -> usize {
+        let home = Self::hash(data[index].key) as usize % data.len();
+        (index + data.len() - home) % data.len()
+    }
+
+    /// Rehashes all keys into a table with capacity cells.
+    pub fn resize(&mut self, capacity: usize) -> Result<(), FlexmapError> {
+        assert!(capacity > self.len);
+        let data = mem::replace(&mut self.data, vec![KHashEntry::default(); capacity]);
+        self.len = 0;
+        for entry in data.into_iter().filter(|entry| !entry.is_empty()) {
+            self.insert(entry.key, entry.range_start, entry.range_len)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts key, the table grows if it would exceed its load factor. Keys
+    /// with an empty range are not stored. Does not check whether key is
+    /// already in the table, see insert_or_update.
+    pub fn insert(&mut self, mut key: u64, mut range_start: u64, mut range_len: u32) -> Result<(), FlexmapError> {
+        if range_len == 0 { return Ok(()) };
+        Self::check_load_factor(self.load_factor)?;
+        if (self.len + 1) as f64 > self.data.len() as f64 * self.load_factor {
+            self.resize(Self::capacity_for(max(2 * self.len, 16), self.load_factor)?)?;
+        }
+        let mut index = Self::hash(key) as usize % self.data.len();
         let mut distance = 0;
+        let mut probes = 0;
 
+        // Robin hood: an entry that is closer to its home cell than the one being
+        // inserted makes room, so that get can stop at the first such entry.
         loop {
-            if unsafe { self.data.get_unchecked(index).is_empty() } {
-                // If the cell is empty, fill it and return
-                let cell = unsafe { self.data.get_unchecked_mut(index) };
-                cell.key = key;
-                cell.range_start = range_start;
-                cell.range_len = range_len;
-
-                return Some(());
+            if self.data[index].is_empty() {
+                self.data[index] = KHashEntry { key, range_start, range_len, reserved: 0 };
+                self.len += 1;
+                return Ok(());
+            }
+            // Only a table without empty cells gets here, e.g. one whose data was replaced.
+            probes += 1;
+            if probes > self.data.len() {
+                return Err(FlexmapError::overflow("probes of a key table insert", probes as u64, self.data.len() as u64));
             }
 
-            let cell_hash_distance = {
-                let cell = unsafe { self.data.get_unchecked(index) };
-                let cell_hash = Self::hash(cell.key as u64) as usize % self.data.len();
-                let cell_hash_distance = if index > cell_hash {
-                    index - cell_hash
-                } else {
-                    index + self.data.len() - cell_hash
-                };
-    
-                cell_hash_distance
-            };
-    
-            // After the block, the mutable borrow ends, and you can safely borrow `self.data` again
-            if cell_hash_distance < distance {
-                // Reborrow the cell mutably just for the swap operation
-                let cell = unsafe { self.data.get_unchecked_mut(index) };
-                std::mem::swap(&mut cell.key, &mut key);
-                std::mem::swap(&mut cell.range_start, &mut range_start);
-                std::mem::swap(&mut cell.range_len, &mut range_len);
-                distance = cell_hash_distance;
+            let cell_distance = Self::probe_distance(&self.data, index);
+            if cell_distance < distance {
+                let cell = &mut self.data[index];
+                mem::swap(&mut cell.key, &mut key);
+                mem::swap(&mut cell.range_start, &mut range_start);
+                mem::swap(&mut cell.range_len, &mut range_len);
+                distance = cell_distance;
             }
-    
-            // Increment the distance and move to the next cell
+
             distance += 1;
             index += 1;
             if index >= self.data.len() { index -= self.data.len() };
+        }
+    }
+
+    /// Replaces the range of key if it is already in the table and inserts it
+    /// otherwise. Returns the previous range. An empty range removes the key.
+    pub fn insert_or_update(&mut self, key: u64, range_start: u64, range_len: u32) -> Result<Option<(usize, usize)>, FlexmapError> {
+        if range_len == 0 {
+            return Ok(self.remove(key));
+        }
+        match self.index(key) {
+            Some(index) => {
+                let cell = &mut self.data[index];
+                let previous = (cell.range_start as usize, cell.range_len as usize);
+                cell.range_start = range_start;
+                cell.range_len = range_len;
+                Ok(Some(previous))
+            }
+            None => {
+                self.insert(key, range_start, range_len)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes key and returns its range. The entries behind it are shifted
+    /// back by one cell until one is at its home cell, so no tombstones are left.
+    pub fn remove(&mut self, key: u64) -> Option<(usize, usize)> {
+        let mut index = self.index(key)?;
+        let removed = mem::take(&mut self.data[index]);
+        self.len -= 1;
 
-            if distance > self.data.len() {
-                panic!("Insert failed due to insufficient coverage");
+        loop {
+            let next = if index + 1 == self.data.len() { 0 } else { index + 1 };
+            if self.data[next].is_empty() || Self::probe_distance(&self.data, next) == 0 {
+                break;
             }
+            self.data[index] = mem::take(&mut self.data[next]);
+            index = next;
         }
+        Some((removed.range_start as usize, removed.range_len as usize))
     }
 
-    pub fn get(&self, key: u32) -> Option<(usize, usize)> {
-        let mut index = Self::hash(key as u64) as usize % self.data.len();
+    pub fn contains(&self, key: u64) -> bool {
+        self.index(key).is_some()
+    }
 
-        let mut distance = 0;
-        let mut cell_hash_distance = {
-            let cell = unsafe { self.data.get_unchecked(index) };
-            let cell_hash = Self::hash(cell.key as u64) as usize % self.data.len();
-            let cell_hash_distance = if index > cell_hash {
-                index - cell_hash
-            } else {
-                index + self.data.len() - cell_hash
-            };
+    /// All keys with their value range, in table order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, (usize, usize))> + '_ {
+        Self::iter_in(&self.data)
+    }
 
-            cell_hash_distance
-        };
+    /// Same as iter but works on a borrowed (part of a) table.
+    pub fn iter_in(data: &[KHashEntry]) -> impl Iterator<Item = (u64, (usize, usize))> + '_ {
+        data.iter()
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| (entry.key, (entry.range_start as usize, entry.range_start as usize + entry.range_len as usize)))
+    }
 
-        while distance <= cell_hash_distance {
-            let cell = unsafe { self.data.get_unchecked(index) };
+    pub fn get(&self, key: u64) -> Option<(usize, usize)> {
+        Self::get_in(&self.data, key)
+    }
 
-            if key == cell.key {
-                return Some((cell.range_start as usize, cell.range_len as usize));
-            }
-            distance += 1;
-            index += 1;
-            if index >= self.data.len() { index -= self.data.len() };
+    /// Same as get but works on a borrowed table, e.g. one that is memory mapped.
+    pub fn get_in(data: &[KHashEntry], key: u64) -> Option<(usize, usize)> {
+        let cell = &data[Self::index_in(data, key)?];
+        Some((cell.range_start as usize, cell.range_len as usize))
+    }
 
-            cell_hash_distance = {
-                let cell = unsafe { self.data.get_unchecked(index) };
-                let cell_hash = Self::hash(cell.key as u64) as usize % self.data.len();
-                let cell_hash_distance = if index > cell_hash {
-                    index - cell_hash
-                } else {
-                    index + self.data.len() - cell_hash
-                };
-    
-                cell_hash_distance
-            };
+    /// Slot of key in the table, e.g. to keep per-key state next to the table.
+    pub fn index(&self, key: u64) -> Option<usize> {
+        Self::index_in(&self.data, key)
+    }
+
+    pub fn index_in(data: &[KHashEntry], key: u64) -> Option<usize> {
+        if data.is_empty() { return None };
+        let mut index = Self::hash(key) as usize % data.len();
 
-            if distance > self.data.len() {
-                panic!("Get failed due to insufficient coverage");
+        for distance in 0..data.len() {
+            let cell = &data[index];
+            // Keys are never behind an empty cell or an entry closer to its home cell.
+            if cell.is_empty() || Self::probe_distance(data, index) < distance {
+                return None;
             }
+            if key == cell.key {
+                return Some(index);
+            }
+            index += 1;
+            if index >= data.len() { index -= data.len() };
         }
         None
     }
 }
 
-
-
 // pub struct HashKeys {
 //     pub data: KeysHashSmall,
 // }
 
 impl FMKeysHash {
     pub fn vrange(&self, canonical_kmer: u64) -> Option<(usize, usize)> {
-        match self.get(canonical_kmer as u32) {
+        match self.get(canonical_kmer) {
             Some(entry) => Some((entry.0, entry.0 + entry.1)),
             None => None,
         }
@@ -487,11 +704,12 @@ impl FMKeysHash {
 #[cfg(test)]
 mod tests {
 
-    use std::{cmp::min, collections::HashMap};
+    use std::collections::HashMap;
 
     use kmerrs::consecutive::kmer::KmerIter;
 
     use super::*;
+    use crate::flexmap::Flexmap;
     use test::Bencher;
 
     #[test]
@@ -543,25 +761,178 @@ mod tests {
         assert_eq!(keys.get_control_header_value(0), 42);
     }
 
+    #[test]
+    fn test_build_repeat_policy() {
+        let counts = |keys: &mut FMKeys<4, 8>| {
+            keys.set_kmer_cell(1, 10);
+            keys.set_kmer_cell(2, 3);
+        };
+        let mut keys = FMKeys::<4, 8>::new();
+        counts(&mut keys);
+        let report = keys.build::<2>(5, RepeatPolicy::Drop);
+        assert_eq!(report, BuildReport { dropped: vec![(1, 10)], subsampled: vec![] });
+        assert_eq!(keys.vrange(1), None);
+        assert_eq!(keys.vrange(2), Some((0, 5)));
+
+        let mut keys = FMKeys::<4, 8>::new();
+        counts(&mut keys);
+        let report = keys.build::<2>(5, RepeatPolicy::Subsample { cap: 4 });
+        assert_eq!(report, BuildReport { dropped: vec![], subsampled: vec![(1, 10)] });
+        assert_eq!(keys.vrange(1), Some((0, 6)));
+
+        // Keys above max_range_size but within cap keep all occurrences.
+        let mut keys = FMKeys::<4, 8>::new();
+        counts(&mut keys);
+        let report = keys.build::<2>(2, RepeatPolicy::Subsample { cap: 4 });
+        assert_eq!(report, BuildReport { dropped: vec![], subsampled: vec![(1, 10)] });
+        let (start, end) = keys.vrange(2).unwrap();
+        assert_eq!(end - start, BlockLayout::for_occurrences::<2>(3).size());
+    }
+
+    #[test]
+    fn test_build_overflow_block() {
+        let mut keys = FMKeys::<4, 8>::new();
+        // 30000 values per key (with header), the offset of the third key no longer fits into a KCell.
+        for kmer in 0..8 {
+            keys.set_kmer_cell(kmer, 20000);
+        }
+        keys.set_kmer_cell(9, 3);
+        keys.set_kmer_cell(10, 1);
+        let report = keys.build::<2>(usize::MAX, RepeatPolicy::Drop);
+        assert_eq!(report, BuildReport::default());
+        assert_eq!(keys.overflow_blocks(), 1);
+
+        for kmer in 0..8 {
+            assert_eq!(keys.vrange(kmer), Some((kmer as usize * 30000, (kmer as usize + 1) * 30000)));
+        }
+        assert_eq!(keys.vrange(8), None);
+        assert_eq!(keys.vrange(9), Some((240000, 240005)));
+        assert_eq!(keys.vrange(10), Some((240005, 240006)));
+        assert_eq!(keys.get_values_size(), 240006);
+
+        let path = std::env::temp_dir().join(format!("flexmap_keys_overflow_{}.idx", std::process::id()));
+        Flexmap::<4, 8, 8, 2>::new(keys.clone()).save(&path).unwrap();
+        let loaded = Flexmap::<4, 8, 8, 2>::load(&path).unwrap().keys;
+        assert_eq!(loaded.overflow, keys.overflow);
+        assert_eq!(loaded.vrange(5), keys.vrange(5));
+        let _ = fs::remove_file(&path);
+
+        // Saturated counts are not trusted
+        let mut keys = FMKeys::<4, 8>::new();
+        keys.set_kmer_cell(1, u16::MAX);
+        let report = keys.build::<2>(usize::MAX, RepeatPolicy::Drop);
+        assert_eq!(report.dropped, vec![(1, u16::MAX as u64)]);
+    }
+
+    #[test]
+    fn test_key_slots() {
+        let mut keys = FMKeys::<4, 8>::new();
+        let kmers = [0, 7, 8, 9, 40, 63, 64, 127, 200, 255];
+        for kmer in kmers {
+            keys.set_kmer_cell(kmer, 2);
+        }
+        keys.build::<2>(usize::MAX, RepeatPolicy::Drop);
+
+        let slots = keys.key_slots().unwrap();
+        assert_eq!(slots.len(), kmers.len());
+        let numbered: Vec<usize> = keys.iter().map(|(kmer, _)| slots.slot(kmer)).collect();
+        assert_eq!(numbered, (0..kmers.len()).collect::<Vec<_>>());
+    }
+
     #[test]
     fn test_fm_keys_hash() {
         let capa = 100_000;
         let mut hashmap = FMKeysHash::with_capacity(capa);
 
         for i in 0..capa {
-            let key = i as u32;
+            let key = i as u64;
             let range_start = i as u64;
-            hashmap.insert(key, range_start, 1);
+            hashmap.insert(key, range_start, 1).unwrap();
             assert!(hashmap.get(key).unwrap() == (range_start as usize, 1))
         }
 
         for i in 0..capa {
-            let key = i as u32;
+            let key = i as u64;
             let range_start = i as u64;
             assert!(hashmap.get(key).unwrap() == (range_start as usize, 1))
         }
     }
 
+    #[test]
+    fn test_fm_keys_hash_grows() {
+        let mut hashmap = FMKeysHash::with_capacity(4);
+        for key in 0..10_000u64 {
+            hashmap.insert(key * 7, key, 1 + key as u32 % 5).unwrap();
+            assert!(hashmap.len() as f64 <= hashmap.capacity() as f64 * hashmap.load_factor);
+        }
+        assert_eq!(hashmap.len(), 10_000);
+        for key in 0..10_000u64 {
+            assert_eq!(hashmap.get(key * 7), Some((key as usize, 1 + key as usize % 5)));
+            assert_eq!(hashmap.get(key * 7 + 1), None);
+        }
+        // Every entry is at most as far from home as the one before it plus one.
+        for index in 0..hashmap.capacity() {
+            let next = (index + 1) % hashmap.capacity();
+            if !hashmap.data[next].is_empty() {
+                assert!(FMKeysHash::probe_distance(&hashmap.data, next) <= FMKeysHash::probe_distance(&hashmap.data, index) + 1);
+            }
+        }
+
+        // Keys that only differ above bit 32 are different keys
+        hashmap.insert(7 | 1 << 40, 1, 1).unwrap();
+        assert_eq!(hashmap.get(7 | 1 << 40), Some((1, 1)));
+        assert_eq!(hashmap.get(7), Some((1, 2)));
+
+        let sized = FMKeysHash::with_expected_keys(10_000);
+        assert!(sized.capacity() as f64 * sized.load_factor >= 10_000.0);
+        assert_eq!(FMKeysHash::with_capacity(0).get(0), None);
+    }
+
+    #[test]
+    fn test_fm_keys_hash_load_factor() {
+        for load_factor in [0.0, -0.5, 1.0, 2.0, f64::NAN] {
+            assert!(matches!(FMKeysHash::from_data(Vec::new(), load_factor), Err(FlexmapError::InvalidLoadFactor(_))));
+            let mut hashmap = FMKeysHash::with_capacity(4);
+            hashmap.load_factor = load_factor;
+            assert!(matches!(hashmap.insert(1, 0, 1), Err(FlexmapError::InvalidLoadFactor(_))));
+        }
+        assert_eq!(FMKeysHash::from_data(vec![KHashEntry::default(); 4], 0.5).unwrap().capacity(), 4);
+
+        // A full table fails instead of probing forever.
+        let mut hashmap = FMKeysHash::with_capacity(16);
+        hashmap.data.iter_mut().for_each(|entry| *entry = KHashEntry { key: 1, range_start: 0, range_len: 1, reserved: 0 });
+        assert!(matches!(hashmap.insert(2, 0, 1), Err(FlexmapError::Overflow { .. })));
+    }
+
+    #[test]
+    fn test_fm_keys_hash_remove_update() {
+        let mut hashmap = FMKeysHash::with_capacity(16);
+        for key in 0..5000u64 {
+            assert_eq!(hashmap.insert_or_update(key, key, 1).unwrap(), None);
+        }
+        assert_eq!(hashmap.insert_or_update(42, 7, 3).unwrap(), Some((42, 1)));
+        assert_eq!(hashmap.len(), 5000);
+        assert_eq!(hashmap.get(42), Some((7, 3)));
+
+        for key in (0..5000u64).step_by(3) {
+            assert!(hashmap.remove(key).is_some());
+        }
+        assert_eq!(hashmap.remove(3), None);
+        assert_eq!(hashmap.insert_or_update(4, 0, 0).unwrap(), Some((4, 1)));
+        assert_eq!(hashmap.len(), 5000 - 1667 - 1);
+
+        for key in 0..5000u64 {
+            assert_eq!(hashmap.contains(key), key % 3 != 0 && key != 4, "key {}", key);
+        }
+        // Backward shifting keeps every entry reachable from its home cell.
+        for index in 0..hashmap.capacity() {
+            let next = (index + 1) % hashmap.capacity();
+            if !hashmap.data[next].is_empty() {
+                assert!(FMKeysHash::probe_distance(&hashmap.data, next) <= FMKeysHash::probe_distance(&hashmap.data, index) + 1);
+            }
+        }
+    }
+
     #[bench]
     fn bench_fm_keys_hash(b: &mut Bencher) {
         let size = 100_000;
@@ -570,15 +941,15 @@ mod tests {
         let mut hashmap = FMKeysHash::with_capacity(capa);
 
         for i in 0..size {
-            let key = i as u32;
+            let key = i as u64;
             let range_start = i as u64;
-            hashmap.insert(key, range_start, 1);
+            hashmap.insert(key, range_start, 1).unwrap();
             assert!(hashmap.get(key).unwrap() == (range_start as usize, 1))
         }
 
         b.iter(|| {
             for i in 0..size {
-                let key = i as u32;
+                let key = i as u64;
                 let range_start = i as u64;
                 assert!(hashmap.get(key).unwrap() == (range_start as usize, 1))
             }
@@ -590,10 +961,10 @@ mod tests {
         let size = 100_000;
         let load_factor = 0.6;
         let capa = (size as f64 * (1.0/load_factor)) as usize;
-        let mut hashmap = HashMap::<u32, (u32, u64)>::with_capacity(capa);
+        let mut hashmap = HashMap::<u64, (u32, u64)>::with_capacity(capa);
 
         for i in 0..size {
-            let key = i as u32;
+            let key = i as u64;
             let range_start = i as u64;
             hashmap.insert(key, (1,  range_start));
             assert!(hashmap.get(&key).unwrap() == &(1, range_start))
@@ -601,7 +972,7 @@ mod tests {
 
         b.iter(|| {
             for i in 0..size {
-                let key = i as u32;
+                let key = i as u64;
                 let range_start = i as u64;
                 assert!(hashmap.get(&key).unwrap() == &(1, range_start))
             }
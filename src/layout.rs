@@ -0,0 +1,90 @@
+use std::mem;
+
+use crate::values::{HeaderSeq, VCell};
+
+/// Layout of one value block
+///
+/// The block of a key with n occurrences holds n positions (VCell). If n is
+/// above HEADER_THRESHOLD the positions are preceded by a header with the
+/// flanks (HeaderSeq) of every position, FLANKS_PER_CELL of them per cell:
+///
+///   start                                        end
+///   ┌───────────────────────────┬─────────────────┐
+///   │ header: ceil(n / 2) cells │ positions: n    │
+///   └───────────────────────────┴─────────────────┘
+///
+/// Keys only know the number of occurrences when they reserve a block
+/// (for_occurrences), values only know the size of the block when they split
+/// it (for_size). Both go through this module so they cannot disagree.
+
+pub const FLANKS_PER_CELL: usize = mem::size_of::<VCell>() / mem::size_of::<HeaderSeq>();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockLayout {
+    /// Cells holding flanks, 0 if the block has no header.
+    pub header: usize,
+    /// Cells holding positions.
+    pub positions: usize,
+}
+
+impl BlockLayout {
+    /// Layout of the block that is reserved for occurrences positions.
+    pub const fn for_occurrences<const HEADER_THRESHOLD: usize>(occurrences: usize) -> Self {
+        let header = if occurrences > HEADER_THRESHOLD { occurrences.div_ceil(FLANKS_PER_CELL) } else { 0 };
+        BlockLayout { header, positions: occurrences }
+    }
+
+    /// Splits a block of size cells into header and positions. Inverse of
+    /// for_occurrences for every size it returns.
+    pub const fn for_size<const HEADER_THRESHOLD: usize>(size: usize) -> Self {
+        let header = if size > HEADER_THRESHOLD { (size + FLANKS_PER_CELL) / (FLANKS_PER_CELL + 1) } else { 0 };
+        BlockLayout { header, positions: size - header }
+    }
+
+    /// Cells of the whole block.
+    pub const fn size(&self) -> usize {
+        self.header + self.positions
+    }
+
+    pub const fn has_header(&self) -> bool {
+        self.header > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_threshold<const HEADER_THRESHOLD: usize>() {
+        for occurrences in 0..5000 {
+            let layout = BlockLayout::for_occurrences::<HEADER_THRESHOLD>(occurrences);
+            assert_eq!(layout.positions, occurrences);
+            assert_eq!(layout.has_header(), occurrences > HEADER_THRESHOLD);
+            assert_eq!(BlockLayout::for_size::<HEADER_THRESHOLD>(layout.size()), layout, "{} occurrences, threshold {}", occurrences, HEADER_THRESHOLD);
+        }
+        for size in 0..7500 {
+            let layout = BlockLayout::for_size::<HEADER_THRESHOLD>(size);
+            assert_eq!(layout.size(), size);
+            // Every position has a flank and no flank lies in the positions.
+            if layout.has_header() {
+                assert!(layout.header * FLANKS_PER_CELL >= layout.positions, "size {}, threshold {}", size, HEADER_THRESHOLD);
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_layout() {
+        assert_eq!(FLANKS_PER_CELL, 2);
+        assert_eq!(BlockLayout::for_size::<2>(8), BlockLayout { header: 3, positions: 5 });
+        assert_eq!(BlockLayout::for_size::<2>(12), BlockLayout { header: 4, positions: 8 });
+
+        check_threshold::<0>();
+        check_threshold::<1>();
+        check_threshold::<2>();
+        check_threshold::<3>();
+        check_threshold::<8>();
+        check_threshold::<16>();
+        check_threshold::<255>();
+        check_threshold::<1000>();
+    }
+}
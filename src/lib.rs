@@ -12,11 +12,19 @@ use values::VData;
 
 const GLOBAL_VERSION: u32 = 1;
 
+pub mod catalog;
+pub mod error;
 pub mod keys;
 pub mod values;
+pub mod layout;
 pub mod flexmap;
 pub mod build;
+pub mod seeds;
+pub mod input;
+pub mod spill;
 pub mod example;
+pub mod format;
+pub mod mapped;
 
 
 #[macro_use]
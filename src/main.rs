@@ -1,10 +1,9 @@
 #![feature(exposed_provenance)]
 use std::{cmp::min, collections::HashMap, fs::File, mem::transmute, path::{Path, PathBuf}};
 
-use flexmap::{example::{build_flexmap, test_flexmap}, flexmap::Flexmap, keys::FMKeys};
+use flexmap::{example::{build_flexmap, test_flexmap}, flexmap::Flexmap, keys::{FMKeys, RepeatPolicy}};
 use kmerrs::consecutive::kmer::{Kmer, KmerIter};
 use bioreader::{fasta_byte_reader, fastq_byte_reader, fasta_reader, fastq_reader};
-use savefile::{load, save};
 
 
 fn test_simple() {
@@ -35,7 +34,9 @@ fn test_simple() {
     keys.set_control_header_value_from_kmer(0, 42);
     println!("{}", keys.get_control_head_value_from_kmer(0));
 
-    keys.build::<2>(100);
+    let report = keys.build::<2>(100, RepeatPolicy::Drop);
+    eprintln!("Skipped {}", report.dropped.len());
+    eprintln!("Subsampled {}", report.subsampled.len());
 
 }
 
@@ -60,21 +61,14 @@ fn main() {
     test_flexmap(&flexmap);
 
     
-    const GLOBAL_VERSION: u32 = 1;
-    let path = PathBuf::from("result/flexmap.save");
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.display(), why),
-        Ok(file) => file,
-    };
-    let _ = save(&mut file, GLOBAL_VERSION, &flexmap);
+    let path = PathBuf::from("result/flexmap.idx");
+    if let Err(why) = flexmap.save(&path) {
+        panic!("couldn't save {}: {}", path.display(), why);
+    }
 
     let keys_path = "/usr/users/QIB_fr017/fritsche/ProjectsPrivate/flexalign/results/keys.bin".to_string();
     // unsafe { flexmap.keys.save_keys(&keys_path) };
 
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.display(), why),
-        Ok(file) => file,
-    };
-    let flexmap: Flexmap<3, 8, 8, 2> = load(&mut file, GLOBAL_VERSION).expect("Loading did not work");
+    let flexmap = Flexmap::<3, 8, 8, 2>::load(&path).expect("Loading did not work");
 
 }
\ No newline at end of file
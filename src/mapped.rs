@@ -0,0 +1,324 @@
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+use std::slice;
+
+use memmap2::Mmap;
+
+use crate::catalog::ReferenceCatalog;
+use crate::error::FlexmapError;
+use crate::flexmap::{FlexIndex, Flexmap, FlexmapHash, VRangeGetter};
+use crate::format::IndexParams;
+use crate::format::{FormatError, IndexHeader, PlainCell, Section, SectionId};
+use crate::keys::{FMKeys, FMKeysHash, KCell, KHashEntry};
+use crate::values::{FMValues, VCell, VRange};
+
+/// Read-only views on a saved index that work directly on a memory map of the
+/// file instead of copying keys and values into the heap. The page cache is shared,
+/// so several processes on one node can query the same index without each holding
+/// a private copy.
+///
+/// The file must not be modified while it is mapped.
+
+fn map_file(path: impl AsRef<Path>) -> Result<(Mmap, IndexHeader), FlexmapError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| FlexmapError::io(path, e))?;
+    let mmap = unsafe { Mmap::map(&file).map_err(|e| FlexmapError::io(path, e))? };
+    let header = IndexHeader::from_bytes(&mmap)?;
+    Ok((mmap, header))
+}
+
+/// Checks that a section lies within the map and is aligned for T.
+fn check_section<T: PlainCell>(mmap: &Mmap, header: &IndexHeader, id: SectionId) -> Result<Section, FormatError> {
+    let section = header.section(id)?;
+    let end = section.offset.checked_add(section.len).ok_or(FormatError::Truncated)?;
+    if end > mmap.len() as u64 || section.len % mem::size_of::<T>() as u64 != 0 {
+        return Err(FormatError::Truncated);
+    }
+    if (mmap.as_ptr() as usize + section.offset as usize) % mem::align_of::<T>() != 0 {
+        return Err(FormatError::InvalidSection(id));
+    }
+    Ok(section)
+}
+
+fn section_slice<T: PlainCell>(mmap: &Mmap, section: Section) -> &[T] {
+    unsafe {
+        slice::from_raw_parts(
+            mmap.as_ptr().add(section.offset as usize) as *const T,
+            section.len as usize / mem::size_of::<T>(),
+        )
+    }
+}
+
+pub struct MappedFlexmap<
+    const C: usize,
+    const F: usize,
+    const CELLS_PER_BODY: u64,
+    const HEADER_THRESHOLD: usize,
+> {
+    mmap: Mmap,
+    header: IndexHeader,
+    keys: Section,
+    overflow: Section,
+    values: Section,
+    catalog: ReferenceCatalog,
+}
+
+impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize>
+    MappedFlexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>
+{
+    /// Maps an index written by Flexmap::save. Fails if the file was built with
+    /// different parameters than the const generics of this type.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FlexmapError> {
+        let (mmap, header) = map_file(path)?;
+        header.check(&Flexmap::<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>::params(header.params.seeding, header.params.layout))?;
+
+        let keys = check_section::<KCell>(&mmap, &header, SectionId::Keys)?;
+        let overflow = check_section::<u64>(&mmap, &header, SectionId::Overflow)?;
+        let values = check_section::<VCell>(&mmap, &header, SectionId::Values)?;
+        if keys.len / mem::size_of::<KCell>() as u64 != FMKeys::<C, CELLS_PER_BODY>::table_size() {
+            return Err(FormatError::Truncated.into());
+        }
+        let values_size = FMKeys::<C, CELLS_PER_BODY>::values_size_in(section_slice(&mmap, keys));
+        if values.len / mem::size_of::<VCell>() as u64 != values_size as u64 {
+            return Err(FormatError::InvalidSection(SectionId::Values).into());
+        }
+
+        let catalog_section = check_section::<u8>(&mmap, &header, SectionId::Catalog)?;
+        let catalog = ReferenceCatalog::from_bytes(section_slice(&mmap, catalog_section))?;
+
+        Ok(Self { mmap, header, keys, overflow, values, catalog })
+    }
+
+    pub fn header(&self) -> &IndexHeader {
+        &self.header
+    }
+
+    pub fn catalog(&self) -> &ReferenceCatalog {
+        &self.catalog
+    }
+
+    pub fn keys(&self) -> &[KCell] {
+        section_slice(&self.mmap, self.keys)
+    }
+
+    pub fn overflow(&self) -> &[u64] {
+        section_slice(&self.mmap, self.overflow)
+    }
+
+    pub fn values(&self) -> &[VCell] {
+        section_slice(&self.mmap, self.values)
+    }
+}
+
+impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize> FlexIndex for
+    MappedFlexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>
+{
+    fn index_params(&self) -> IndexParams {
+        self.header.params
+    }
+
+    fn catalog(&self) -> &ReferenceCatalog {
+        &self.catalog
+    }
+
+    fn iter_vranges(&self) -> Box<dyn Iterator<Item = (u64, VRange<'_>)> + Send + '_> {
+        let layout = self.header.params.layout;
+        Box::new(FMKeys::<C, CELLS_PER_BODY>::iter_in(self.keys(), self.overflow(), 0..1 << (2 * C))
+            .map(move |(kmer, range)| (kmer, FMValues::<F, HEADER_THRESHOLD>::range_in(self.values(), range, layout))))
+    }
+
+    /// Bytes of the mapped sections, they are shared with other processes mapping the same file.
+    fn memory_usage(&self) -> usize {
+        (self.keys.len + self.overflow.len + self.values.len) as usize
+    }
+}
+
+impl<const C: usize, const F: usize, const CELLS_PER_BODY: u64, const HEADER_THRESHOLD: usize> VRangeGetter for
+    MappedFlexmap<C, F, CELLS_PER_BODY, HEADER_THRESHOLD>
+{
+    fn get_vrange(&self, canonical_kmer: u64) -> Option<VRange> {
+        let range = FMKeys::<C, CELLS_PER_BODY>::vrange_in(self.keys(), self.overflow(), canonical_kmer)?;
+        Some(FMValues::<F, HEADER_THRESHOLD>::range_in(self.values(), range, self.header.params.layout))
+    }
+}
+
+
+pub struct MappedFlexmapHash<
+    const C: usize,
+    const F: usize,
+    const HEADER_THRESHOLD: usize,
+> {
+    mmap: Mmap,
+    header: IndexHeader,
+    keys: Section,
+    values: Section,
+    catalog: ReferenceCatalog,
+}
+
+impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize>
+    MappedFlexmapHash<C, F, HEADER_THRESHOLD>
+{
+    /// Maps an index written by FlexmapHash::save. Fails if the file was built with
+    /// different parameters than the const generics of this type.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FlexmapError> {
+        let (mmap, header) = map_file(path)?;
+        header.check(&FlexmapHash::<C, F, HEADER_THRESHOLD>::params(header.params.seeding, header.params.layout))?;
+
+        let keys = check_section::<KHashEntry>(&mmap, &header, SectionId::Keys)?;
+        let values = check_section::<VCell>(&mmap, &header, SectionId::Values)?;
+        if keys.len == 0 {
+            return Err(FormatError::Truncated.into());
+        }
+        // Every range has to lie within the values, lookups do not check them.
+        let values_size = values.len / mem::size_of::<VCell>() as u64;
+        if FMKeysHash::iter_in(section_slice(&mmap, keys)).any(|(_, (_, end))| end as u64 > values_size) {
+            return Err(FormatError::InvalidSection(SectionId::Values).into());
+        }
+
+        let catalog_section = check_section::<u8>(&mmap, &header, SectionId::Catalog)?;
+        let catalog = ReferenceCatalog::from_bytes(section_slice(&mmap, catalog_section))?;
+
+        Ok(Self { mmap, header, keys, values, catalog })
+    }
+
+    pub fn header(&self) -> &IndexHeader {
+        &self.header
+    }
+
+    pub fn catalog(&self) -> &ReferenceCatalog {
+        &self.catalog
+    }
+
+    pub fn keys(&self) -> &[KHashEntry] {
+        section_slice(&self.mmap, self.keys)
+    }
+
+    pub fn values(&self) -> &[VCell] {
+        section_slice(&self.mmap, self.values)
+    }
+}
+
+impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize> FlexIndex for
+    MappedFlexmapHash<C, F, HEADER_THRESHOLD>
+{
+    fn index_params(&self) -> IndexParams {
+        self.header.params
+    }
+
+    fn catalog(&self) -> &ReferenceCatalog {
+        &self.catalog
+    }
+
+    fn iter_vranges(&self) -> Box<dyn Iterator<Item = (u64, VRange<'_>)> + Send + '_> {
+        let layout = self.header.params.layout;
+        Box::new(FMKeysHash::iter_in(self.keys())
+            .map(move |(kmer, range)| (kmer, FMValues::<F, HEADER_THRESHOLD>::range_in(self.values(), range, layout))))
+    }
+
+    /// Bytes of the mapped sections, they are shared with other processes mapping the same file.
+    fn memory_usage(&self) -> usize {
+        (self.keys.len + self.values.len) as usize
+    }
+}
+
+impl<const C: usize, const F: usize, const HEADER_THRESHOLD: usize> VRangeGetter for
+    MappedFlexmapHash<C, F, HEADER_THRESHOLD>
+{
+    fn get_vrange(&self, canonical_kmer: u64) -> Option<VRange> {
+        let range = FMKeysHash::get_in(self.keys(), canonical_kmer)?;
+        Some(FMValues::<F, HEADER_THRESHOLD>::range_in(self.values(), (range.0, range.0 + range.1), self.header.params.layout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::FlexmapError;
+    use crate::flexmap::{Flexmap, FlexmapHash, VRangeGetter};
+    use crate::keys::{FMKeys, FMKeysHash, RepeatPolicy};
+
+    use super::*;
+
+    #[test]
+    fn test_mapped_flexmap() {
+        let mut keys = FMKeys::<3, 8>::new();
+        for kmer in [1, 5, 6, 6, 6, 9, 9, 9, 9] {
+            keys.get_kmer_cell_mut_ref(kmer).increment();
+        }
+        keys.build::<2>(100, RepeatPolicy::Drop);
+        let mut flexmap = Flexmap::<3, 8, 8, 2>::new(keys);
+        for (idx, cell) in flexmap.values.data.iter_mut().enumerate() {
+            cell.set_raw(idx as u64 + 1);
+        }
+
+        let path = std::env::temp_dir().join(format!("flexmap_test_mapped_flexmap_{}.idx", std::process::id()));
+        flexmap.save(&path).unwrap();
+        let mapped = MappedFlexmap::<3, 8, 8, 2>::open(&path).unwrap();
+
+        for kmer in 0..64 {
+            let expected = flexmap.get_vrange(kmer).map(|r| r.positions.iter().map(|c| c.0).collect::<Vec<_>>());
+            let found = mapped.get_vrange(kmer).map(|r| r.positions.iter().map(|c| c.0).collect::<Vec<_>>());
+            assert_eq!(expected, found);
+        }
+        let cores = |index: &dyn FlexIndex| index.iter_vranges().map(|(kmer, r)| (kmer, r.positions.len())).collect::<Vec<_>>();
+        assert_eq!(cores(&mapped), cores(&flexmap));
+        assert_eq!(mapped.index_params(), flexmap.index_params());
+        assert!(MappedFlexmap::<3, 8, 16, 2>::open(&path).is_err());
+
+        // Values that do not match the keys are rejected when the file is opened.
+        flexmap.values.data.pop();
+        flexmap.save(&path).unwrap();
+        assert!(matches!(MappedFlexmap::<3, 8, 8, 2>::open(&path), Err(FlexmapError::FormatMismatch(FormatError::InvalidSection(SectionId::Values)))));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_mapped_flexmap_hash() {
+        let mut keys = FMKeysHash::with_capacity(16);
+        keys.insert(3, 0, 2).unwrap();
+        keys.insert(7, 2, 4).unwrap();
+        let mut flexmap = FlexmapHash::<3, 8, 2>::new(keys);
+        for (idx, cell) in flexmap.values.data.iter_mut().enumerate() {
+            cell.set_raw(idx as u64 + 1);
+        }
+
+        let path = std::env::temp_dir().join(format!("flexmap_test_mapped_flexmap_hash_{}.idx", std::process::id()));
+        flexmap.save(&path).unwrap();
+        let mapped = MappedFlexmapHash::<3, 8, 2>::open(&path).unwrap();
+
+        for kmer in 0..16 {
+            let expected = flexmap.get_vrange(kmer).map(|r| r.positions.iter().map(|c| c.0).collect::<Vec<_>>());
+            let found = mapped.get_vrange(kmer).map(|r| r.positions.iter().map(|c| c.0).collect::<Vec<_>>());
+            assert_eq!(expected, found);
+        }
+
+        flexmap.values.data.pop();
+        flexmap.save(&path).unwrap();
+        assert!(matches!(MappedFlexmapHash::<3, 8, 2>::open(&path), Err(FlexmapError::FormatMismatch(FormatError::InvalidSection(SectionId::Values)))));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_mapped_flexmap_hash_long_cores() {
+        // Cores of 21 nt that agree in their lower 32 bits
+        let cores = [5u64, 5 | 1 << 40, 5 | 1 << 41];
+        let mut keys = FMKeysHash::with_capacity(16);
+        keys.insert(cores[0], 0, 2).unwrap();
+        keys.insert(cores[1], 2, 1).unwrap();
+        let mut flexmap = FlexmapHash::<21, 8, 2>::new(keys);
+        for (idx, cell) in flexmap.values.data.iter_mut().enumerate() {
+            cell.set_raw(idx as u64 + 1);
+        }
+
+        let path = std::env::temp_dir().join(format!("flexmap_test_mapped_flexmap_hash_long_{}.idx", std::process::id()));
+        flexmap.save(&path).unwrap();
+        let mapped = MappedFlexmapHash::<21, 8, 2>::open(&path).unwrap();
+
+        let expected = [Some(vec![1, 2]), Some(vec![3]), None];
+        for (core, expected) in cores.iter().zip(expected) {
+            assert_eq!(flexmap.get_vrange(*core).map(|r| r.positions.iter().map(|c| c.0).collect::<Vec<_>>()), expected);
+            assert_eq!(mapped.get_vrange(*core).map(|r| r.positions.iter().map(|c| c.0).collect::<Vec<_>>()), expected);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::VecDeque;
+
+use kmerrs::{consecutive::kmer::{Kmer, KmerIter}, syncmer::closed_syncmer::ClosedSyncmer};
+
+use crate::build::canonical;
+use crate::error::FlexmapError;
+use crate::format::{FormatError, SeedParams};
+use crate::keys::FMKeysHash;
+use crate::values::Strand;
+
+/// Seed selection
+///
+/// Every k-mer of a sequence is a candidate, its key in the index is the
+/// canonical C-core. A SeedSelection picks the candidates that become seeds.
+/// The build records the parameters of the selection in the index
+/// (SeedParams), SeedSelector::from_params recreates the same selection on the
+/// query side so that reads are seeded like the references.
+
+/// One selected k-mer of a sequence.
+#[derive(Clone, Copy)]
+pub struct Seed<const K: usize, const C: usize> {
+    /// Position of the k-mer in the sequence.
+    pub pos: usize,
+    /// Canonical core, the key in the index.
+    pub core: Kmer<C>,
+    /// The k-mer in the orientation of the canonical core.
+    pub kmer: Kmer<K>,
+    pub strand: Strand,
+}
+
+/// Strategy that picks the seeds of a sequence.
+pub trait SeedSelection<const K: usize, const C: usize> {
+    /// Parameters recorded in the index.
+    fn params(&self) -> SeedParams;
+
+    /// Number of k-mers on either side of a k-mer that decide whether it is a seed.
+    fn context(&self) -> usize {
+        0
+    }
+
+    /// Seeds of seq in sequence order.
+    fn seeds<'a>(&'a mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>;
+
+    /// Like seeds, but the iterator owns the selection.
+    fn into_seeds<'a>(self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>
+    where
+        Self: Sized + 'a;
+}
+
+/// Seed of a read with the flanks of its k-mer, see Flexmap::seeds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadSeed {
+    /// Position of the k-mer in the read.
+    pub pos: usize,
+    /// Orientation of the read relative to the canonical core.
+    pub strand: Strand,
+    pub core: u64,
+    /// Flanks of the k-mer in the orientation of the core, comparable to the
+    /// flank header of the value block (HeaderSeq).
+    pub flanks: u32,
+}
+
+/// Seeds of read selected like the references of an index built with seeding.
+/// Fails if the index was built with other K, S or L.
+pub fn read_seeds<'a, const K: usize, const C: usize, const F: usize, const S: usize, const L: usize>(
+    seeding: &SeedParams,
+    read: &'a [u8],
+) -> Result<impl Iterator<Item = ReadSeed> + 'a, FlexmapError> {
+    let selector = SeedSelector::<K, C, S, L>::from_params(seeding)?;
+    Ok(selector.into_seeds(read).map(|seed| ReadSeed {
+        pos: seed.pos,
+        strand: seed.strand,
+        core: seed.core.0,
+        flanks: seed.kmer.flanks::<F>().0 as u32,
+    }))
+}
+
+/// How seeds are selected, chosen when an index is built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SeedScheme {
+    /// Every k-mer is a seed.
+    All,
+    /// K-mers whose core is a closed syncmer.
+    #[default]
+    ClosedSyncmer,
+    /// K-mers whose core has its smallest s-mer at offset.
+    OpenSyncmer { offset: usize },
+    /// (window, k) minimizers: the k-mer with the smallest core of every
+    /// window consecutive k-mers.
+    Minimizer { window: usize },
+}
+
+impl SeedScheme {
+    /// Code of the scheme in SeedParams.
+    pub const fn code(&self) -> u32 {
+        match self {
+            SeedScheme::All => 1,
+            SeedScheme::ClosedSyncmer => 2,
+            SeedScheme::OpenSyncmer { .. } => 3,
+            SeedScheme::Minimizer { .. } => 4,
+        }
+    }
+}
+
+/// All k-mers of a sequence with their canonical cores.
+fn candidates<const K: usize, const C: usize>(seq: &[u8]) -> impl Iterator<Item = Seed<K, C>> + '_ {
+    KmerIter::<K, true>::new(seq).map(|(pos, kmer_fwd, kmer_rev)| {
+        let (core, kmer, strand) = canonical::<K, C>(kmer_fwd, kmer_rev);
+        Seed { pos, core, kmer, strand }
+    })
+}
+
+pub struct AllKmers<const K: usize, const C: usize>;
+
+impl<const K: usize, const C: usize> SeedSelection<K, C> for AllKmers<K, C> {
+    fn params(&self) -> SeedParams {
+        SeedParams { scheme: SeedScheme::All.code(), k: K as u32, ..SeedParams::default() }
+    }
+
+    fn seeds<'a>(&'a mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a> {
+        Box::new(candidates::<K, C>(seq))
+    }
+
+    fn into_seeds<'a>(self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>
+    where
+        Self: 'a,
+    {
+        Box::new(candidates::<K, C>(seq))
+    }
+}
+
+pub struct ClosedSyncmers<const K: usize, const C: usize, const S: usize, const L: usize> {
+    syncmer: ClosedSyncmer<C, S, L>,
+}
+
+impl<const K: usize, const C: usize, const S: usize, const L: usize> ClosedSyncmers<K, C, S, L> {
+    pub fn new() -> Self {
+        ClosedSyncmers { syncmer: ClosedSyncmer::<C, S, L>::new() }
+    }
+}
+
+impl<const K: usize, const C: usize, const S: usize, const L: usize> Default for ClosedSyncmers<K, C, S, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const K: usize, const C: usize, const S: usize, const L: usize> SeedSelection<K, C> for ClosedSyncmers<K, C, S, L> {
+    fn params(&self) -> SeedParams {
+        SeedParams { scheme: SeedScheme::ClosedSyncmer.code(), k: K as u32, s: S as u32, l: L as u32, ..SeedParams::default() }
+    }
+
+    fn seeds<'a>(&'a mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a> {
+        Box::new(candidates::<K, C>(seq).filter(|seed| self.syncmer.is_minimizer(seed.core.0)))
+    }
+
+    fn into_seeds<'a>(mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>
+    where
+        Self: 'a,
+    {
+        Box::new(candidates::<K, C>(seq).filter(move |seed| self.syncmer.is_minimizer(seed.core.0)))
+    }
+}
+
+/// Cores whose smallest s-mer, ordered by hash, starts at offset. Ties go to
+/// the leftmost s-mer.
+pub struct OpenSyncmers<const K: usize, const C: usize, const S: usize> {
+    pub offset: usize,
+}
+
+impl<const K: usize, const C: usize, const S: usize> OpenSyncmers<K, C, S> {
+    /// offset must be a position of an s-mer in a core, SeedSelector::new checks it.
+    pub fn new(offset: usize) -> Self {
+        assert!(S <= C && offset <= C - S, "open syncmer offset {} outside of the {} s-mers of a core", offset, C + 1 - S);
+        OpenSyncmers { offset }
+    }
+
+    /// Position of the smallest s-mer of core.
+    pub fn smallest_smer(core: u64) -> usize {
+        let mask = (1u64 << (2 * S)) - 1;
+        (0..=C - S).min_by_key(|&i| FMKeysHash::hash((core >> (2 * (C - S - i))) & mask)).unwrap_or(0)
+    }
+}
+
+impl<const K: usize, const C: usize, const S: usize> SeedSelection<K, C> for OpenSyncmers<K, C, S> {
+    fn params(&self) -> SeedParams {
+        SeedParams { scheme: SeedScheme::OpenSyncmer { offset: self.offset }.code(), k: K as u32, s: S as u32, offset: self.offset as u32, ..SeedParams::default() }
+    }
+
+    fn seeds<'a>(&'a mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a> {
+        let offset = self.offset;
+        Box::new(candidates::<K, C>(seq).filter(move |seed| Self::smallest_smer(seed.core.0) == offset))
+    }
+
+    fn into_seeds<'a>(self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>
+    where
+        Self: 'a,
+    {
+        let offset = self.offset;
+        Box::new(candidates::<K, C>(seq).filter(move |seed| Self::smallest_smer(seed.core.0) == offset))
+    }
+}
+
+/// (window, k) minimizers ordered by the hash of the core, ties go to the
+/// leftmost k-mer. A sequence with fewer than window k-mers yields its
+/// smallest one.
+pub struct Minimizers<const K: usize, const C: usize> {
+    pub window: usize,
+}
+
+impl<const K: usize, const C: usize> Minimizers<K, C> {
+    /// window must not be 0, SeedSelector::new checks it.
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0, "minimizer window must not be empty");
+        Minimizers { window }
+    }
+}
+
+impl<const K: usize, const C: usize> SeedSelection<K, C> for Minimizers<K, C> {
+    fn params(&self) -> SeedParams {
+        SeedParams { scheme: SeedScheme::Minimizer { window: self.window }.code(), k: K as u32, window: self.window as u32, ..SeedParams::default() }
+    }
+
+    fn context(&self) -> usize {
+        self.window - 1
+    }
+
+    fn seeds<'a>(&'a mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a> {
+        Box::new(MinimizerIter { candidates: candidates::<K, C>(seq), window: self.window, queue: VecDeque::new(), seen: 0, last: None })
+    }
+
+    fn into_seeds<'a>(self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>
+    where
+        Self: 'a,
+    {
+        Box::new(MinimizerIter { candidates: candidates::<K, C>(seq), window: self.window, queue: VecDeque::new(), seen: 0, last: None })
+    }
+}
+
+struct MinimizerIter<const K: usize, const C: usize, I> {
+    candidates: I,
+    window: usize,
+    /// (index, hash, seed) of the candidates that can still be the minimum of
+    /// a window, with increasing index and hash.
+    queue: VecDeque<(usize, u64, Seed<K, C>)>,
+    seen: usize,
+    last: Option<usize>,
+}
+
+impl<const K: usize, const C: usize, I: Iterator<Item = Seed<K, C>>> Iterator for MinimizerIter<K, C, I> {
+    type Item = Seed<K, C>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(seed) = self.candidates.next() else {
+                // Sequence shorter than a window, its only window is the whole sequence.
+                if self.last.is_none() && self.seen > 0 && self.seen < self.window {
+                    self.last = Some(0);
+                    return self.queue.front().map(|&(_, _, seed)| seed);
+                }
+                return None;
+            };
+            let hash = FMKeysHash::hash(seed.core.0);
+            while self.queue.back().is_some_and(|&(_, back, _)| back > hash) {
+                self.queue.pop_back();
+            }
+            self.queue.push_back((self.seen, hash, seed));
+            self.seen += 1;
+            if self.queue.front().is_some_and(|&(index, _, _)| index + self.window < self.seen) {
+                self.queue.pop_front();
+            }
+            if self.seen < self.window { continue };
+
+            let &(index, _, seed) = self.queue.front().expect("Window is not empty");
+            if self.last != Some(index) {
+                self.last = Some(index);
+                return Some(seed);
+            }
+        }
+    }
+}
+
+/// Selection chosen at runtime. All passes of a build use the same selector so
+/// that counting and filling agree.
+pub enum SeedSelector<const K: usize, const C: usize, const S: usize, const L: usize> {
+    All(AllKmers<K, C>),
+    ClosedSyncmer(ClosedSyncmers<K, C, S, L>),
+    OpenSyncmer(OpenSyncmers<K, C, S>),
+    Minimizer(Minimizers<K, C>),
+}
+
+impl<const K: usize, const C: usize, const S: usize, const L: usize> SeedSelector<K, C, S, L> {
+    /// Fails if scheme selects no seeds: an open syncmer offset past the last
+    /// s-mer of a core or an empty minimizer window.
+    pub fn new(scheme: SeedScheme) -> Result<Self, FlexmapError> {
+        Ok(match scheme {
+            SeedScheme::All => SeedSelector::All(AllKmers),
+            SeedScheme::ClosedSyncmer => SeedSelector::ClosedSyncmer(ClosedSyncmers::new()),
+            SeedScheme::OpenSyncmer { offset } if S <= C && offset <= C - S => SeedSelector::OpenSyncmer(OpenSyncmers::new(offset)),
+            SeedScheme::Minimizer { window } if window > 0 => SeedSelector::Minimizer(Minimizers::new(window)),
+            _ => return Err(FlexmapError::InvalidSeedScheme(scheme)),
+        })
+    }
+
+    /// Recreates the selection an index was built with. Fails if the index
+    /// was built with other K, S or L.
+    pub fn from_params(params: &SeedParams) -> Result<Self, FlexmapError> {
+        let scheme = match params.scheme {
+            1 => SeedScheme::All,
+            2 => SeedScheme::ClosedSyncmer,
+            3 => SeedScheme::OpenSyncmer { offset: params.offset as usize },
+            4 => SeedScheme::Minimizer { window: params.window as usize },
+            other => return Err(FlexmapError::FormatMismatch(FormatError::UnknownSeedScheme(other))),
+        };
+        let selector = Self::new(scheme)?;
+        let expected = selector.params();
+        for (name, expected, found) in [("k", expected.k, params.k), ("s", expected.s, params.s), ("l", expected.l, params.l)] {
+            if expected != found {
+                return Err(FlexmapError::FormatMismatch(FormatError::ParameterMismatch { name, expected: expected as u64, found: found as u64 }));
+            }
+        }
+        Ok(selector)
+    }
+}
+
+impl<const K: usize, const C: usize, const S: usize, const L: usize> SeedSelection<K, C> for SeedSelector<K, C, S, L> {
+    fn params(&self) -> SeedParams {
+        match self {
+            SeedSelector::All(selection) => selection.params(),
+            SeedSelector::ClosedSyncmer(selection) => selection.params(),
+            SeedSelector::OpenSyncmer(selection) => selection.params(),
+            SeedSelector::Minimizer(selection) => selection.params(),
+        }
+    }
+
+    fn context(&self) -> usize {
+        match self {
+            SeedSelector::All(selection) => selection.context(),
+            SeedSelector::ClosedSyncmer(selection) => selection.context(),
+            SeedSelector::OpenSyncmer(selection) => selection.context(),
+            SeedSelector::Minimizer(selection) => selection.context(),
+        }
+    }
+
+    fn seeds<'a>(&'a mut self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a> {
+        match self {
+            SeedSelector::All(selection) => selection.seeds(seq),
+            SeedSelector::ClosedSyncmer(selection) => selection.seeds(seq),
+            SeedSelector::OpenSyncmer(selection) => selection.seeds(seq),
+            SeedSelector::Minimizer(selection) => selection.seeds(seq),
+        }
+    }
+
+    fn into_seeds<'a>(self, seq: &'a [u8]) -> Box<dyn Iterator<Item = Seed<K, C>> + 'a>
+    where
+        Self: 'a,
+    {
+        match self {
+            SeedSelector::All(selection) => selection.into_seeds(seq),
+            SeedSelector::ClosedSyncmer(selection) => selection.into_seeds(seq),
+            SeedSelector::OpenSyncmer(selection) => selection.into_seeds(seq),
+            SeedSelector::Minimizer(selection) => selection.into_seeds(seq),
+        }
+    }
+}
+
+impl<const K: usize, const C: usize, const S: usize, const L: usize> Default for SeedSelector<K, C, S, L> {
+    fn default() -> Self {
+        Self::new(SeedScheme::default()).expect("Closed syncmers need no parameters")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQ: &[u8] = b"ACGTTGCAAGGCTTACCGATCGATTTGACCAGTAGGCATCAAGTCCGATGCATTGACGTAGCTAGGATCCA";
+
+    fn positions<S: SeedSelection<15, 11>>(selection: &mut S, seq: &[u8]) -> Vec<usize> {
+        selection.seeds(seq).map(|seed| seed.pos).collect()
+    }
+
+    #[test]
+    fn test_seeds_match_syncmer() {
+        let mut selector = SeedSelector::<15, 11, 3, 2>::default();
+        let seeds: Vec<_> = selector.seeds(SEQ).map(|seed| (seed.pos, seed.core.0)).collect();
+
+        let mut syncmer = ClosedSyncmer::<11, 3, 2>::new();
+        let expected: Vec<_> = KmerIter::<15, true>::new(SEQ)
+            .map(|(pos, fwd, rev)| (pos, canonical::<15, 11>(fwd, rev).0 .0))
+            .filter(|&(_, core)| syncmer.is_minimizer(core))
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(seeds, expected);
+
+        // Selecting twice gives the same seeds, as counting and filling rely on.
+        assert_eq!(selector.seeds(SEQ).count(), seeds.len());
+        assert_eq!(selector.params(), SeedParams { scheme: 2, k: 15, s: 3, l: 2, offset: 0, window: 0 });
+    }
+
+    #[test]
+    fn test_open_syncmers() {
+        let mut open = OpenSyncmers::<15, 11, 3>::new(2);
+        let seeds: Vec<_> = open.seeds(SEQ).collect();
+        assert!(seeds.iter().all(|seed| OpenSyncmers::<15, 11, 3>::smallest_smer(seed.core.0) == 2));
+        // Every candidate is an open syncmer for exactly one offset.
+        let total: usize = (0..=8).map(|offset| OpenSyncmers::<15, 11, 3>::new(offset).seeds(SEQ).count()).sum();
+        assert_eq!(total, AllKmers::<15, 11>.seeds(SEQ).count());
+    }
+
+    #[test]
+    fn test_minimizers() {
+        let hashes: Vec<u64> = AllKmers::<15, 11>.seeds(SEQ).map(|seed| FMKeysHash::hash(seed.core.0)).collect();
+        for window in [1, 2, 5, 10, hashes.len(), hashes.len() + 3] {
+            // Leftmost smallest k-mer of every window, computed naively.
+            let mut expected: Vec<usize> = hashes.windows(window.min(hashes.len()))
+                .enumerate()
+                .map(|(start, w)| start + w.iter().enumerate().min_by_key(|&(i, h)| (*h, i)).unwrap().0)
+                .collect();
+            expected.dedup();
+            assert_eq!(positions(&mut Minimizers::new(window), SEQ), expected, "window {}", window);
+        }
+        assert_eq!(positions(&mut Minimizers::new(1), SEQ), positions(&mut AllKmers, SEQ));
+        assert!(positions(&mut Minimizers::new(4), b"ACGT").is_empty());
+    }
+
+    #[test]
+    fn test_selector_from_params() {
+        for scheme in [SeedScheme::All, SeedScheme::ClosedSyncmer, SeedScheme::OpenSyncmer { offset: 4 }, SeedScheme::Minimizer { window: 7 }] {
+            let mut selector = SeedSelector::<15, 11, 3, 2>::new(scheme).unwrap();
+            let params = selector.params();
+            let mut restored = SeedSelector::<15, 11, 3, 2>::from_params(&params).unwrap();
+            assert_eq!(restored.params(), params);
+            assert_eq!(positions(&mut restored, SEQ), positions(&mut selector, SEQ));
+        }
+        let params = SeedSelector::<15, 11, 3, 2>::default().params();
+        assert!(SeedSelector::<17, 11, 3, 2>::from_params(&params).is_err());
+        assert!(SeedSelector::<15, 11, 3, 2>::from_params(&SeedParams::default()).is_err());
+    }
+
+    #[test]
+    fn test_selector_rejects_invalid_schemes() {
+        assert!(SeedSelector::<15, 11, 3, 2>::new(SeedScheme::OpenSyncmer { offset: 8 }).is_ok());
+        for scheme in [SeedScheme::OpenSyncmer { offset: 9 }, SeedScheme::Minimizer { window: 0 }] {
+            assert!(matches!(SeedSelector::<15, 11, 3, 2>::new(scheme), Err(FlexmapError::InvalidSeedScheme(s)) if s == scheme));
+            // A corrupt index with such a scheme fails to load instead of panicking.
+            let params = SeedParams { scheme: scheme.code(), k: 15, s: 3, offset: 9, ..SeedParams::default() };
+            assert!(matches!(SeedSelector::<15, 11, 3, 2>::from_params(&params), Err(FlexmapError::InvalidSeedScheme(_))));
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::FlexmapError;
+use crate::values::VCell;
+
+/// Where and with how much memory the single-pass build keeps its seeds.
+#[derive(Clone, Debug)]
+pub struct SpillOptions {
+    /// Directory for the temporary runs, they are deleted after the build.
+    pub dir: PathBuf,
+    /// Bytes of seeds all workers together buffer before writing a run.
+    pub memory: usize,
+}
+
+impl SpillOptions {
+    pub fn new(dir: impl AsRef<Path>, memory: usize) -> Self {
+        SpillOptions { dir: dir.as_ref().to_path_buf(), memory }
+    }
+}
+
+/// One occurrence of a core, as collected by the single-pass build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeedRecord {
+    pub cmer: u64,
+    /// Packed reference id, position and strand (see VCell).
+    pub cell: u64,
+    pub flanks: u32,
+}
+
+impl Ord for SeedRecord {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.order().cmp(&other.order())
+            .then(self.cell.cmp(&other.cell))
+            .then(self.flanks.cmp(&other.flanks))
+    }
+}
+
+impl PartialOrd for SeedRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl SeedRecord {
+    pub const BYTES: usize = 20;
+
+    /// Runs are sorted by core, then by reference id and position, which is
+    /// the order the values of a key are stored in.
+    fn order(&self) -> (u64, u64) {
+        (self.cmer, VCell(self.cell).payload())
+    }
+
+    fn to_bytes(self) -> [u8; Self::BYTES] {
+        let mut bytes = [0u8; Self::BYTES];
+        bytes[0..8].copy_from_slice(&self.cmer.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.cell.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.flanks.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::BYTES]) -> Self {
+        SeedRecord {
+            cmer: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            cell: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            flanks: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        }
+    }
+}
+
+/// Numbers the run files of all writers of this process.
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes sorted records as a new run in dir and registers it in runs.
+fn write_run(dir: &Path, records: impl Iterator<Item = Result<SeedRecord, FlexmapError>>, runs: &mut Vec<PathBuf>) -> Result<(), FlexmapError> {
+    let run = NEXT_RUN.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!("flexmap-run-{}-{}.bin", std::process::id(), run));
+    // Registered first, so that a partially written run is removed as well.
+    runs.push(path.clone());
+
+    let file = File::create(&path).map_err(|e| FlexmapError::io(&path, e))?;
+    let mut writer = BufWriter::new(file);
+    for record in records {
+        writer.write_all(&record?.to_bytes()).map_err(|e| FlexmapError::io(&path, e))?;
+    }
+    writer.flush().map_err(|e| FlexmapError::io(&path, e))
+}
+
+/// Buffers seeds and writes them as sorted runs once capacity seeds are buffered.
+pub struct SpillWriter {
+    dir: PathBuf,
+    capacity: usize,
+    buffer: Vec<SeedRecord>,
+    runs: Vec<PathBuf>,
+}
+
+impl SpillWriter {
+    /// memory is the budget of this writer in bytes.
+    pub fn new(dir: &Path, memory: usize) -> Self {
+        let capacity = (memory / mem::size_of::<SeedRecord>()).max(1);
+        SpillWriter { dir: dir.to_path_buf(), capacity, buffer: Vec::new(), runs: Vec::new() }
+    }
+
+    pub fn push(&mut self, record: SeedRecord) -> Result<(), FlexmapError> {
+        self.buffer.push(record);
+        if self.buffer.len() >= self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), FlexmapError> {
+        if self.buffer.is_empty() { return Ok(()) };
+        self.buffer.sort_unstable();
+        write_run(&self.dir, self.buffer.drain(..).map(Ok), &mut self.runs)
+    }
+
+    /// Writes the remaining seeds and returns the runs.
+    pub fn finish(mut self) -> Result<Runs, FlexmapError> {
+        let result = self.spill();
+        let runs = Runs(mem::take(&mut self.runs));
+        result.map(|_| runs)
+    }
+}
+
+impl Drop for SpillWriter {
+    /// Only removes runs of writers that were not finished, e.g. after an error.
+    fn drop(&mut self) {
+        drop(Runs(mem::take(&mut self.runs)));
+    }
+}
+
+/// Temporary run files, deleted on drop.
+#[derive(Default)]
+pub struct Runs(Vec<PathBuf>);
+
+impl Runs {
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.0
+    }
+
+    pub fn extend(&mut self, mut other: Runs) {
+        self.0.append(&mut other.0);
+    }
+
+    /// Most runs read at once, more runs are first merged into intermediate runs.
+    const MAX_FAN_IN: usize = 64;
+
+    /// Iterates over the seeds of all runs in sorted order.
+    pub fn merge(&mut self) -> Result<RunMerger, FlexmapError> {
+        while self.0.len() > Self::MAX_FAN_IN {
+            let dir = self.0[0].parent().map(Path::to_path_buf).unwrap_or_default();
+            let groups = self.0.len().div_ceil(Self::MAX_FAN_IN);
+            let mut merged = Runs::default();
+            for group in self.0.chunks(self.0.len().div_ceil(groups)) {
+                write_run(&dir, RunMerger::open(group)?, &mut merged.0)?;
+            }
+            // Removes the runs that were just merged.
+            *self = merged;
+        }
+        RunMerger::open(&self.0)
+    }
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// k-way merge of sorted runs.
+pub struct RunMerger {
+    readers: Vec<(PathBuf, BufReader<File>)>,
+    heap: BinaryHeap<Reverse<(SeedRecord, usize)>>,
+}
+
+impl RunMerger {
+    fn open(paths: &[PathBuf]) -> Result<Self, FlexmapError> {
+        let mut readers = Vec::with_capacity(paths.len());
+        for path in paths {
+            let file = File::open(path).map_err(|e| FlexmapError::io(path, e))?;
+            readers.push((path.clone(), BufReader::new(file)));
+        }
+        let mut merger = RunMerger { readers, heap: BinaryHeap::new() };
+        for run in 0..merger.readers.len() {
+            merger.advance(run)?;
+        }
+        Ok(merger)
+    }
+
+    /// Reads the next seed of run into the heap.
+    fn advance(&mut self, run: usize) -> Result<(), FlexmapError> {
+        let (path, reader) = &mut self.readers[run];
+        let mut bytes = [0u8; SeedRecord::BYTES];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {
+                self.heap.push(Reverse((SeedRecord::from_bytes(&bytes), run)));
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => Err(FlexmapError::io(path, e)),
+        }
+    }
+}
+
+impl Iterator for RunMerger {
+    type Item = Result<SeedRecord, FlexmapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((record, run)) = self.heap.pop()?;
+        if let Err(e) = self.advance(run) {
+            return Some(Err(e));
+        }
+        Some(Ok(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_merge_sorted() {
+        let dir = std::env::temp_dir();
+        // Room for 3 seeds per run.
+        let mut writer = SpillWriter::new(&dir, 3 * mem::size_of::<SeedRecord>());
+        let mut other = SpillWriter::new(&dir, 3 * mem::size_of::<SeedRecord>());
+        let mut expected = Vec::new();
+        for idx in 0..20u64 {
+            let record = SeedRecord { cmer: (idx * 7) % 5, cell: 1 << 34 | (idx * 13) % 17 | ((idx % 2) << 63), flanks: idx as u32 };
+            expected.push(record);
+            if idx % 3 == 0 { other.push(record).unwrap() } else { writer.push(record).unwrap() };
+        }
+        expected.sort();
+
+        let mut runs = writer.finish().unwrap();
+        runs.extend(other.finish().unwrap());
+        assert_eq!(runs.paths().len(), 8);
+        let merged: Vec<SeedRecord> = runs.merge().unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(merged, expected);
+
+        let paths = runs.paths().to_vec();
+        drop(runs);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn test_spill_merge_in_passes() {
+        let dir = std::env::temp_dir();
+        // One seed per run, more runs than a single pass reads.
+        let mut writer = SpillWriter::new(&dir, mem::size_of::<SeedRecord>());
+        let mut expected = Vec::new();
+        for idx in 0..150u64 {
+            let record = SeedRecord { cmer: (idx * 31) % 11, cell: 1 << 34 | (idx * 13) % 97, flanks: idx as u32 };
+            expected.push(record);
+            writer.push(record).unwrap();
+        }
+        expected.sort();
+
+        let mut runs = writer.finish().unwrap();
+        let written = runs.paths().to_vec();
+        assert_eq!(written.len(), 150);
+        let merged: Vec<SeedRecord> = runs.merge().unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(merged, expected);
+        assert_eq!(runs.paths().len(), 3);
+        assert!(written.iter().all(|path| !path.exists()));
+
+        let paths = runs.paths().to_vec();
+        drop(runs);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+}
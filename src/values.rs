@@ -1,14 +1,15 @@
 use std::cmp::Ordering::{Equal, Greater, Less};
-use std::fs::File;
 use std::io::{Read, Write};
 use std::iter::zip;
-use std::mem;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
 use std::{cmp::Ordering, fmt::Display, slice};
 
 use bincode::{Decode, Encode};
 use kmerrs::consecutive::kmer::Kmer;
 
-use crate::VD;
+use crate::error::FlexmapError;
+use crate::layout::BlockLayout;
 
 /// Values holds the sequence positions a kmer occurs in
 /// Each key in the keys points to a region in values
@@ -58,6 +59,67 @@ impl HeaderSeq {
 
         (a | b).count_ones()
     }
+
+    /// Mismatches at the bases selected by mask, 0b11 per base.
+    pub fn masked_dist(&self, flex: u32, mask: u32) -> u32 {
+        let diff = (self.0 ^ flex) & mask;
+        ((diff | (diff >> 1)) & 0x55555555).count_ones()
+    }
+
+    /// Mismatches in the left and the right flank, counting only the bases
+    /// selected by mask.
+    pub fn flank_dist<const F: usize>(&self, flex: u32, mask: u32) -> FlankDist {
+        FlankDist {
+            left: self.masked_dist(flex, mask & Flank::Left.mask::<F>()),
+            right: self.masked_dist(flex, mask & Flank::Right.mask::<F>()),
+        }
+    }
+}
+
+/// Side of the core a flank lies on. Kmer::flanks puts the F/2 bases of the
+/// left flank above the F/2 bases of the right flank.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flank {
+    Left,
+    Right,
+}
+
+impl Flank {
+    /// Bits of the flank in a HeaderSeq. A query that only knows one flank,
+    /// e.g. near the end of a read, compares with the mask of that flank.
+    pub const fn mask<const F: usize>(&self) -> u32 {
+        let bits = 2 * (F / 2);
+        let right = ((1u64 << bits) - 1) as u32;
+        match self {
+            Flank::Left => right << bits,
+            Flank::Right => right,
+        }
+    }
+
+    /// Bits of both flanks.
+    pub const fn both<const F: usize>() -> u32 {
+        Flank::Left.mask::<F>() | Flank::Right.mask::<F>()
+    }
+}
+
+/// Mismatches in the left and in the right flank of an occurrence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlankDist {
+    pub left: u32,
+    pub right: u32,
+}
+
+impl FlankDist {
+    pub fn total(&self) -> u32 {
+        self.left + self.right
+    }
+
+    pub fn side(&self, flank: Flank) -> u32 {
+        match flank {
+            Flank::Left => self.left,
+            Flank::Right => self.right,
+        }
+    }
 }
 
 pub struct VData<const VAL_BITS: usize, const POS_BITS: usize>();
@@ -66,6 +128,11 @@ impl<const VAL_BITS: usize, const POS_BITS: usize> VData<VAL_BITS, POS_BITS> {
     const POS_MASK: u64 = (1 << POS_BITS) - 1;
     const VAL_MASK: u64 = (1 << VAL_BITS) - 1;
 
+    /// Bit split (value bits, position bits) recorded in the index header.
+    pub const fn bits() -> (usize, usize) {
+        (VAL_BITS, POS_BITS)
+    }
+
     pub const fn get(data: u64) -> (u64, u64) {
         let val = (data >> POS_BITS) & Self::VAL_MASK;
         let pos = data & Self::POS_MASK;
@@ -80,12 +147,102 @@ impl<const VAL_BITS: usize, const POS_BITS: usize> VData<VAL_BITS, POS_BITS> {
     }
 }
 
+/// Bit layout of the occurrences stored in a VCell
+///
+///  63     62 .. val_bits+pos_bits   val_bits+pos_bits-1 .. pos_bits   pos_bits-1 .. 0
+/// strand  unused                    reference id (value)               position
+///
+/// Indexes of many short contigs need more value bits, indexes of few large
+/// chromosomes more position bits. The layout is recorded in the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Savefile, ser_raw::Serialize, Encode, Decode)]
+#[repr(C)]
+pub struct ValueLayout {
+    pub val_bits: u32,
+    pub pos_bits: u32,
+}
+
+impl Default for ValueLayout {
+    fn default() -> Self {
+        Self::of::<28, 34>()
+    }
+}
+
+impl ValueLayout {
+    /// Bits available for value and position, the top bit holds the strand.
+    pub const PAYLOAD_BITS: u32 = 63;
+
+    pub fn new(val_bits: u32, pos_bits: u32) -> Result<Self, FlexmapError> {
+        if val_bits == 0 || pos_bits == 0 || val_bits + pos_bits > Self::PAYLOAD_BITS {
+            return Err(FlexmapError::overflow("value layout bits", (val_bits + pos_bits) as u64, Self::PAYLOAD_BITS as u64));
+        }
+        Ok(ValueLayout { val_bits, pos_bits })
+    }
+
+    pub const fn of<const VAL_BITS: usize, const POS_BITS: usize>() -> Self {
+        assert!(VAL_BITS > 0 && POS_BITS > 0 && VAL_BITS + POS_BITS <= Self::PAYLOAD_BITS as usize);
+        ValueLayout { val_bits: VAL_BITS as u32, pos_bits: POS_BITS as u32 }
+    }
+
+    pub const fn max_val(&self) -> u64 {
+        (1 << self.val_bits) - 1
+    }
+
+    pub const fn max_pos(&self) -> u64 {
+        (1 << self.pos_bits) - 1
+    }
+
+    /// Packs value (reference id) and position, fails instead of masking if one does not fit.
+    pub fn pack(&self, val: u64, pos: u64) -> Result<u64, FlexmapError> {
+        if val > self.max_val() {
+            return Err(FlexmapError::overflow("reference id", val, self.max_val()));
+        }
+        if pos > self.max_pos() {
+            return Err(FlexmapError::overflow("position", pos, self.max_pos()));
+        }
+        Ok(val << self.pos_bits | pos)
+    }
+
+    /// Returns (value, position).
+    pub const fn unpack(&self, data: u64) -> (u64, u64) {
+        ((data >> self.pos_bits) & self.max_val(), data & self.max_pos())
+    }
+}
+
+/// Orientation of an occurrence: Forward if the canonical core equals the
+/// reference sequence at that position, Reverse if it is its reverse complement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+impl Strand {
+    pub fn symbol(&self) -> char {
+        match self {
+            Strand::Forward => '+',
+            Strand::Reverse => '-',
+        }
+    }
+}
+
 #[derive(Clone, Savefile, ser_raw::Serialize, Encode, Decode)]
 #[repr(C)]
 pub struct VCell(pub u64);
 
 impl VCell {
-    const MASK: u64 = (1 << 60) - 1;
+    const MASK: u64 = (1 << ValueLayout::PAYLOAD_BITS) - 1;
+    const STRAND_BIT: u64 = 1 << ValueLayout::PAYLOAD_BITS;
+
+    pub fn set_strand(&mut self, strand: Strand) {
+        match strand {
+            Strand::Forward => self.0 &= !Self::STRAND_BIT,
+            Strand::Reverse => self.0 |= Self::STRAND_BIT,
+        }
+    }
+
+    pub fn strand(&self) -> Strand {
+        if self.0 & Self::STRAND_BIT == 0 { Strand::Forward } else { Strand::Reverse }
+    }
 
     pub fn set_raw(&mut self, value: u64) {
         self.0 = value;
@@ -99,25 +256,39 @@ impl VCell {
         self.0
     }
 
+    /// Packed reference id and position without the strand bit.
+    pub fn payload(&self) -> u64 {
+        self.0 & Self::MASK
+    }
+
     pub fn empty(&self) -> bool {
         self.0 == 0
     }
+
+    /// Occurrences are ordered by reference id and position, empty cells last.
+    /// Reference ids are assigned in input order, so this is the order a
+    /// sequential build inserts them in.
+    fn order(&self) -> u64 {
+        if self.empty() { u64::MAX } else { self.payload() }
+    }
 }
 
 #[derive(Clone)]
 pub struct VRange<'a> {
     pub header: Option<&'a [HeaderSeq]>,
     pub positions: &'a [VCell],
+    pub layout: ValueLayout,
 }
 
 pub struct VRangeMut<'a> {
     pub header: Option<&'a mut [HeaderSeq]>,
     pub positions: &'a mut [VCell],
+    pub layout: ValueLayout,
 }
 
 impl<'a> VRange<'a> {
-    pub fn new(header: Option<&'a [HeaderSeq]>, positions: &'a [VCell]) -> Self {
-        Self { header, positions }
+    pub fn new(header: Option<&'a [HeaderSeq]>, positions: &'a [VCell], layout: ValueLayout) -> Self {
+        Self { header, positions, layout }
     }
 }
 
@@ -182,6 +353,17 @@ impl<'a> Ord for VRange<'a> {
     }
 }
 
+/// Occurrence of a core whose flanks are close to the queried ones, see
+/// VRange::flex_matches_within.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlexMatch {
+    pub rpos: u64,
+    pub rval: u64,
+    pub strand: Strand,
+    /// Mismatches between the flanks, None if the block has no header.
+    pub dist: Option<FlankDist>,
+}
+
 impl<'a> VRange<'a> {
     pub fn to_verbose_string(&self) -> String { //<const V: usize, const P: usize>
         let mut str = String::new();
@@ -189,32 +371,54 @@ impl<'a> VRange<'a> {
             Some(header) => {
                 assert_eq!(header.len(), self.positions.len());
                 for idx in 0..header.len() {
-                    let (val, pos) = VD::get(self.positions[idx].0);
-                    str.push_str(&format!("{}: {} {}\n", header[idx].to_string(), val, pos));
+                    let (val, pos) = self.layout.unpack(self.positions[idx].0);
+                    let strand = self.positions[idx].strand().symbol();
+                    str.push_str(&format!("{}: {} {} {}\n", header[idx].to_string(), val, pos, strand));
                 }
                 return str;
             }
             None => {
                 for idx in 0..self.positions.len() {
-                    let (val, pos) = VD::get(self.positions[idx].0);
-                    str.push_str(&format!(".............. : {} {}\n", val, pos));
+                    let (val, pos) = self.layout.unpack(self.positions[idx].0);
+                    let strand = self.positions[idx].strand().symbol();
+                    str.push_str(&format!(".............. : {} {} {}\n", val, pos, strand));
                 }
                 return str;
             }
         }
     }
 
-    pub fn best_flex_match<const F: usize, L>(&self, flex: &Kmer<F>, mut lambda: L)
+    /// Calls lambda with the occurrences whose flanks have the fewest
+    /// mismatches to flex over both sides, with their distance per side and
+    /// the number of such occurrences.
+    pub fn best_flex_match<const F: usize, L>(&self, flex: &Kmer<F>, lambda: L)
+    where
+        L: FnMut(u64, u64, Strand, Option<(FlankDist, u32)>) -> (), // Put in struct: rpos, rval, strand, Option(distance, count)
+    {
+        self.best_match_by::<F, _, _>(flex, Flank::both::<F>(), |dist| dist.total(), lambda)
+    }
+
+    /// Like best_flex_match, but only the mismatches in one flank count, so
+    /// that mismatches in the other one do not hide an exact match. flex only
+    /// needs to hold that flank, the other side is reported with distance 0.
+    pub fn best_flank_match<const F: usize, L>(&self, flex: &Kmer<F>, flank: Flank, lambda: L)
     where
-        L: FnMut(u64, u64, Option<(u32, u32)>) -> (), // Put in struct: rpos, rval, Option(distance, count)
+        L: FnMut(u64, u64, Strand, Option<(FlankDist, u32)>) -> (),
     {
+        self.best_match_by::<F, _, _>(flex, flank.mask::<F>(), |dist| dist.side(flank), lambda)
+    }
 
+    fn best_match_by<const F: usize, D, L>(&self, flex: &Kmer<F>, mask: u32, key: D, mut lambda: L)
+    where
+        D: Fn(&FlankDist) -> u32,
+        L: FnMut(u64, u64, Strand, Option<(FlankDist, u32)>) -> (),
+    {
         match self.header {
             Some(headers) => {
                 let mut count = 0;
                 let mut min_dist = u32::MAX;
                 for header in headers {
-                    let dist = header.dist(flex.0 as u32);
+                    let dist = key(&header.flank_dist::<F>(flex.0 as u32, mask));
                     if dist < min_dist {
                         min_dist = dist;
                         count = 0;
@@ -224,35 +428,65 @@ impl<'a> VRange<'a> {
                     };
                 }
                 
-                // eprintln!("Header------");
                 for (index, header) in headers.iter().enumerate() {
-                    let dist = header.dist(flex.0 as u32);
-                    if dist == min_dist {
-                        let (value, rpos) = VD::get(self.positions[index].0);
+                    let dist = header.flank_dist::<F>(flex.0 as u32, mask);
+                    if key(&dist) == min_dist {
+                        let (value, rpos) = self.layout.unpack(self.positions[index].0);
 
-                        lambda(rpos, value, Some((dist, count)));
+                        lambda(rpos, value, self.positions[index].strand(), Some((dist, count)));
                     }
                 }
             }
             None => {
                 for cell in self.positions {
                     // self.seeds.push((*pos, cell.clone()));
-                    let (value, rpos) = VD::get(cell.0);
-                    lambda(rpos, value, None);
+                    let (value, rpos) = self.layout.unpack(cell.0);
+                    lambda(rpos, value, cell.strand(), None);
                 }
             }
         };
     }
 
 
+    /// All occurrences whose flanks differ from flex in at most max_dist
+    /// bases, nearest first and in block order within the same distance, at
+    /// most max_hits of them. Blocks without a header cannot be filtered, their
+    /// first max_hits occurrences are returned without a distance.
+    pub fn flex_matches_within<const F: usize>(&self, flex: &Kmer<F>, max_dist: u32, max_hits: usize) -> Vec<FlexMatch> {
+        self.flex_matches_masked(flex, Flank::both::<F>(), max_dist, max_hits)
+    }
+
+    /// Like flex_matches_within for a partial flex, only the bases selected
+    /// by mask (see Flank::mask) are compared.
+    pub fn flex_matches_masked<const F: usize>(&self, flex: &Kmer<F>, mask: u32, max_dist: u32, max_hits: usize) -> Vec<FlexMatch> {
+        let matched = |index: usize, dist: Option<FlankDist>| {
+            let (rval, rpos) = self.layout.unpack(self.positions[index].0);
+            FlexMatch { rpos, rval, strand: self.positions[index].strand(), dist }
+        };
+        match self.header {
+            Some(headers) => {
+                let mut near: Vec<(u32, usize, FlankDist)> = headers.iter().enumerate()
+                    .map(|(index, header)| {
+                        let dist = header.flank_dist::<F>(flex.0 as u32, mask);
+                        (dist.total(), index, dist)
+                    })
+                    .filter(|&(total, _, _)| total <= max_dist)
+                    .collect();
+                near.sort_unstable_by_key(|&(total, index, _)| (total, index));
+                near.into_iter().take(max_hits).map(|(_, index, dist)| matched(index, Some(dist))).collect()
+            }
+            None => (0..self.positions.len()).take(max_hits).map(|index| matched(index, None)).collect(),
+        }
+    }
+
     pub fn all_matches<L>(&self, mut lambda: L)
     where
-        L: FnMut(u64, u64) -> (), // Put in struct: rpos, rval, Option(distance, count)
+        L: FnMut(u64, u64, Strand) -> (), // Put in struct: rpos, rval, strand
     {
         for cell in self.positions {
             // self.seeds.push((*pos, cell.clone()));
-            let (value, rpos) = VD::get(cell.0);
-            lambda(rpos, value);
+            let (value, rpos) = self.layout.unpack(cell.0);
+            lambda(rpos, value, cell.strand());
         }
     }
 
@@ -262,27 +496,27 @@ impl<'a> VRange<'a> {
 }
 
 impl<'a> VRangeMut<'a> {
-    pub fn insert(&mut self, value: u64, flanks: u32) -> () {
-        match &mut self.header {
-            Some(header) => {
-                assert_eq!(header.len(), self.positions.len());
-                for idx in 0..self.positions.len() {
-                    if self.positions[idx].empty() {
-                        self.positions[idx].set(value);
-                        header[idx].set(flanks);
-                        break;
-                    }
-                }
-            }
-            None => {
-                for idx in 0..self.positions.len() {
-                    if self.positions[idx].empty() {
-                        self.positions[idx].set(value);
-                        break;
-                    }
-                }
-            }
+    /// Inserts an occurrence into slot, which the caller takes from a fill
+    /// cursor of the key (see FillCursors). flanks must be taken from the k-mer
+    /// in the same orientation as the canonical core, i.e. the one given by strand.
+    /// Fails if the block has no such slot or if reference id or position do
+    /// not fit into the value layout.
+    pub fn insert(&mut self, slot: usize, reference_id: u64, pos: u64, strand: Strand, flanks: u32) -> Result<(), FlexmapError> {
+        let mut cell = VCell(self.layout.pack(reference_id, pos)?);
+        cell.set_strand(strand);
+        self.insert_cell(slot, cell, flanks)
+    }
+
+    /// Same as insert for an already packed cell.
+    pub fn insert_cell(&mut self, slot: usize, cell: VCell, flanks: u32) -> Result<(), FlexmapError> {
+        if slot >= self.positions.len() {
+            return Err(FlexmapError::BlockFull { capacity: self.positions.len() });
         }
+        self.positions[slot] = cell;
+        if let Some(header) = &mut self.header {
+            header[slot].set(flanks);
+        }
+        Ok(())
     }
 }
 
@@ -307,8 +541,40 @@ impl<'a> Display for VRange<'a> {
 }
 
 impl<'a> VRangeMut<'a> {
-    pub fn new(header: Option<&'a mut [HeaderSeq]>, positions: &'a mut [VCell]) -> Self {
-        Self { header, positions }
+    pub fn new(header: Option<&'a mut [HeaderSeq]>, positions: &'a mut [VCell], layout: ValueLayout) -> Self {
+        Self { header, positions, layout }
+    }
+
+    /// Returns (cell, flanks) of all cells, flanks are 0 if the block has no header.
+    pub fn occurrences(&self) -> Vec<(VCell, u32)> {
+        match &self.header {
+            Some(header) => self.positions.iter().cloned().zip(header.iter().map(|h| h.get())).collect(),
+            None => self.positions.iter().map(|cell| (cell.clone(), 0)).collect(),
+        }
+    }
+
+    /// Overwrites the block with occurrences, cells beyond them are emptied.
+    pub fn set_occurrences(&mut self, occurrences: &[(VCell, u32)]) {
+        for idx in 0..self.positions.len() {
+            let (cell, flanks) = occurrences.get(idx).cloned().unwrap_or((VCell(0), 0));
+            self.positions[idx] = cell;
+            if let Some(header) = &mut self.header {
+                header[idx].set(flanks);
+            }
+        }
+    }
+
+    /// Orders the occurrences by reference id and position, empty cells last.
+    /// Flanks in the header move with their cell.
+    pub fn sort(&mut self) {
+        match self.header {
+            Some(_) => {
+                let mut occurrences = self.occurrences();
+                occurrences.sort_unstable_by_key(|(cell, _)| cell.order());
+                self.set_occurrences(&occurrences);
+            }
+            None => self.positions.sort_unstable_by_key(VCell::order),
+        }
     }
 
     fn to_verbose_string<const V: usize, const P: usize>(
@@ -319,8 +585,8 @@ impl<'a> VRangeMut<'a> {
             Some(header) => {
                 assert_eq!(header.len(), self.positions.len());
                 for idx in 0..header.len() {
-                    let (val, pos) = VD::get(self.positions[idx].0);
-                    let _ = write!(f, "{}: {} {}\n", header[idx].to_string(), val, pos);
+                    let (val, pos) = self.layout.unpack(self.positions[idx].0);
+                    let _ = write!(f, "{}: {} {} {}\n", header[idx].to_string(), val, pos, self.positions[idx].strand().symbol());
                 }
                 let mut string = String::new();
                 f.write_str(&string);
@@ -328,8 +594,8 @@ impl<'a> VRangeMut<'a> {
             }
             None => {
                 for idx in 0..self.positions.len() {
-                    let (val, pos) = VD::get(self.positions[idx].0);
-                    let _ = write!(f, "............. {} {}\n", val, pos);
+                    let (val, pos) = self.layout.unpack(self.positions[idx].0);
+                    let _ = write!(f, "............. {} {} {}\n", val, pos, self.positions[idx].strand().symbol());
                 }
                 let mut string = String::new();
                 f.write_str(&string);
@@ -343,61 +609,70 @@ impl<'a> VRangeMut<'a> {
 #[repr(C)]
 pub struct FMValues<const F: usize, const HEADER_THRESHOLD: usize> {
     pub data: Vec<VCell>,
+    pub layout: ValueLayout,
 }
 
 impl<const F: usize, const HEADER_THRESHOLD: usize> FMValues<F, HEADER_THRESHOLD> {
     pub fn new(size: usize) -> Self {
+        Self::with_layout(size, ValueLayout::default())
+    }
+
+    pub fn with_layout(size: usize, layout: ValueLayout) -> Self {
         FMValues {
             data: vec![VCell(0); size],
+            layout,
         }
     }
 
     pub fn with_capacity(size: usize) -> Self {
         FMValues {
             data: Vec::with_capacity(size),
+            layout: ValueLayout::default(),
         }
     }
 
-    pub fn get_header_size(vblock_size: usize) -> usize {
-        (vblock_size + 2) / 3
+    pub fn get_range(&self, range: (usize, usize)) -> VRange {
+        Self::range_in(&self.data, range, self.layout)
     }
 
-    pub fn get_range(&self, range: (usize, usize)) -> VRange {
+    /// Same as get_range but works on borrowed values, e.g. ones that are memory mapped.
+    pub fn range_in(data: &[VCell], range: (usize, usize), layout: ValueLayout) -> VRange {
         let (start, end) = range;
-        let size = end - start;
+        let block = BlockLayout::for_size::<HEADER_THRESHOLD>(end - start);
 
-        if size > HEADER_THRESHOLD {
-            let header_size = Self::get_header_size(size);
-            let values_size = size - header_size;
-            let header_slice = &self.data[start..start + header_size];
+        if block.has_header() {
+            let header_slice = &data[start..start + block.header];
             let header = unsafe {
-                slice::from_raw_parts(header_slice.as_ptr() as *const HeaderSeq, values_size)
+                slice::from_raw_parts(header_slice.as_ptr() as *const HeaderSeq, block.positions)
             };
-            let vr = VRange::new(Some(header), &self.data[start + header_size..end]);
+            let vr = VRange::new(Some(header), &data[start + block.header..end], layout);
             vr
         } else {
-            let vr = VRange::new(None, &self.data[start..end]);
+            let vr = VRange::new(None, &data[start..end], layout);
             vr
         }
         // let v = unsafe { slice::from_raw_parts(value.as_ptr() as *const i8, value.len()) };
     }
 
     pub fn get_range_mut(&mut self, range: (usize, usize)) -> VRangeMut {
+        Self::range_mut_in(&mut self.data, range, self.layout)
+    }
+
+    /// Same as get_range_mut but works on a borrowed part of the values.
+    pub fn range_mut_in(data: &mut [VCell], range: (usize, usize), layout: ValueLayout) -> VRangeMut {
         let (start, end) = range;
-        let size: usize = end - start;
+        let block = BlockLayout::for_size::<HEADER_THRESHOLD>(end - start);
 
-        if size > HEADER_THRESHOLD {
-            let header_size = Self::get_header_size(size);
-            let values_size = size - header_size;
-            let header_slice = &mut self.data[start..start + header_size];
+        if block.has_header() {
+            let (header_slice, positions) = data[start..end].split_at_mut(block.header);
             let header: &mut [HeaderSeq] = unsafe {
-                slice::from_raw_parts_mut(header_slice.as_mut_ptr() as *mut HeaderSeq, values_size)
+                slice::from_raw_parts_mut(header_slice.as_mut_ptr() as *mut HeaderSeq, block.positions)
             };
-            let vr = VRangeMut::new(Some(header), &mut self.data[start + header_size..end]);
+            let vr = VRangeMut::new(Some(header), positions, layout);
             vr
         } else {
             // println!("{} {} -> {}, HT {} HAS HEADER {} SLICESIZE {} len data {}", start, end, size, HEADER_THRESHOLD, size > HEADER_THRESHOLD, end - start, self.data.len());
-            let vr = VRangeMut::new(None, &mut self.data[start..end]);
+            let vr = VRangeMut::new(None, &mut data[start..end], layout);
 
             // let slice = &mut self.data[start..end];
             vr
@@ -405,59 +680,71 @@ impl<const F: usize, const HEADER_THRESHOLD: usize> FMValues<F, HEADER_THRESHOLD
         // let v = unsafe { slice::from_raw_parts(value.as_ptr() as *const i8, value.len()) };
     }
 
-    pub fn save(&mut self, filename: &String) -> () {
-        let mut f = File::create(&filename).expect("no file found");
-        // Convert Vec<u16> to raw bytes
-        let bytes: &[u8] = unsafe {
-            // Get a raw pointer to the vector's data
-            let ptr = self.data.as_ptr();
-
-            // Calculate the length of the data in bytes
-            let len = self.data.len() * mem::size_of::<u64>();
-
-            // Create a slice of u8 from the raw pointer and length
-            std::slice::from_raw_parts(ptr as *const u8, len)
-        };
-
-        f.write_all(bytes);
+    /// Write access for several threads, see SharedValues.
+    pub fn shared(&mut self) -> SharedValues<F, HEADER_THRESHOLD> {
+        SharedValues { data: self.data.as_mut_ptr(), len: self.data.len(), layout: self.layout, _values: PhantomData }
     }
+}
 
-    pub fn load(filename: &String) -> FMValues<F, HEADER_THRESHOLD> {
-        let mut f = File::open(&filename).expect("no file found");
+/// Lets several threads fill the values at once. Every slot of a block may
+/// only be written by one thread, the build guarantees this by handing out
+/// slots through per-key atomic fill cursors.
+pub struct SharedValues<'a, const F: usize, const HEADER_THRESHOLD: usize> {
+    data: *mut VCell,
+    len: usize,
+    layout: ValueLayout,
+    _values: PhantomData<&'a mut FMValues<F, HEADER_THRESHOLD>>,
+}
 
-        // Determine the length of the file
-        let metadata = match f.metadata() {
-            Ok(metadata) => metadata,
-            Err(e) => {
-                panic!("Error getting file metadata: {}", e)
-            }
-        };
+unsafe impl<'a, const F: usize, const HEADER_THRESHOLD: usize> Send for SharedValues<'a, F, HEADER_THRESHOLD> {}
+unsafe impl<'a, const F: usize, const HEADER_THRESHOLD: usize> Sync for SharedValues<'a, F, HEADER_THRESHOLD> {}
 
-        let file_size = metadata.len() as usize;
+impl<'a, const F: usize, const HEADER_THRESHOLD: usize> SharedValues<'a, F, HEADER_THRESHOLD> {
+    /// Writes cell and flanks to slot of the block range. Fails without
+    /// writing if the block has no such slot, i.e. it is full.
+    pub fn write(&self, range: (usize, usize), slot: usize, cell: VCell, flanks: u32) -> Result<(), FlexmapError> {
+        let (start, end) = range;
+        assert!(start <= end && end <= self.len);
+        let block = BlockLayout::for_size::<HEADER_THRESHOLD>(end - start);
+        if slot >= block.positions {
+            return Err(FlexmapError::BlockFull { capacity: block.positions });
+        }
+        // Bounds are checked above, exclusiveness of slot is the callers promise.
+        unsafe {
+            *self.data.add(start + block.header + slot) = cell;
+            if block.has_header() {
+                *(self.data.add(start) as *mut HeaderSeq).add(slot) = HeaderSeq(flanks);
+            }
+        }
+        Ok(())
+    }
 
-        let mut keys = Self::with_capacity(file_size / 8);
+    /// The block range, e.g. to sort it.
+    ///
+    /// # Safety
+    /// No other thread may access the cells of range while the block is alive.
+    pub unsafe fn range_mut(&self, range: (usize, usize)) -> VRangeMut<'a> {
+        let (start, end) = range;
+        assert!(start <= end && end <= self.len);
+        let data = unsafe { slice::from_raw_parts_mut(self.data.add(start), end - start) };
+        FMValues::<F, HEADER_THRESHOLD>::range_mut_in(data, (0, end - start), self.layout)
+    }
+}
 
-        // Calculate the number of u16 elements to read
-        let num_u16_elements = file_size / mem::size_of::<VCell>();
+/// Number of occurrences inserted so far for every key. Handing out slots
+/// from here makes inserting constant time and lets several threads fill
+/// different slots of the same block.
+pub struct FillCursors(Vec<AtomicU32>);
 
-        // Use unsafe code to reinterpret vec_u16 as a Vec<u8>
-        let vec_u8: &mut [u8] = unsafe {
-            // Get a mutable reference to the entire vec_u16's buffer as u8
-            let ptr = keys.data.as_mut_ptr() as *mut u8;
-            std::slice::from_raw_parts_mut(ptr, num_u16_elements * mem::size_of::<VCell>())
-        };
+impl FillCursors {
+    /// keys is the number of key indexes, e.g. the key slots of FMKeys or the table size of FMKeysHash.
+    pub fn new(keys: usize) -> Self {
+        FillCursors((0..keys).map(|_| AtomicU32::new(0)).collect())
+    }
 
-        // Read u8 data directly into vec_u8
-        match f.read_exact(vec_u8) {
-            Ok(_) => {
-                // At this point, vec_u16 contains the data read from the file
-                println!("Data read from file: success");
-            }
-            Err(e) => {
-                panic!("Error reading from file: {}", e);
-            }
-        }
-        keys
+    /// Claims the next slot of the block of key_index.
+    pub fn next(&self, key_index: usize) -> usize {
+        self.0[key_index].fetch_add(1, AtomicOrdering::Relaxed) as usize
     }
 }
 
@@ -471,11 +758,153 @@ mod tests {
 
     #[test]
     fn test_kmer_to_indexes_1() {
-        assert_eq!(FMValues::<16, 2>::get_header_size(5), 2); // 2+3
-        assert_eq!(FMValues::<16, 2>::get_header_size(6), 2); // 2+4
-        assert_eq!(FMValues::<16, 2>::get_header_size(8), 3); // 3+5
-        assert_eq!(FMValues::<16, 2>::get_header_size(9), 3); // 3+6
-        assert_eq!(FMValues::<16, 2>::get_header_size(11), 4); // 4+7
-        assert_eq!(FMValues::<16, 2>::get_header_size(12), 4); // 4+8
+        assert_eq!(BlockLayout::for_size::<2>(5).header, 2); // 2+3
+        assert_eq!(BlockLayout::for_size::<2>(6).header, 2); // 2+4
+        assert_eq!(BlockLayout::for_size::<2>(8).header, 3); // 3+5
+        assert_eq!(BlockLayout::for_size::<2>(9).header, 3); // 3+6
+        assert_eq!(BlockLayout::for_size::<2>(11).header, 4); // 4+7
+        assert_eq!(BlockLayout::for_size::<2>(12).header, 4); // 4+8
+    }
+
+    #[test]
+    fn test_strand_roundtrip() {
+        let mut values = FMValues::<16, 2>::new(8);
+        {
+            let mut vblock = values.get_range_mut((0, 8));
+            for pos in 0..5u64 {
+                let strand = if pos % 2 == 0 { Strand::Forward } else { Strand::Reverse };
+                vblock.insert(pos as usize, 1, pos, strand, pos as u32 * 7).unwrap();
+            }
+            assert!(matches!(vblock.insert(5, 1, 5, Strand::Forward, 0), Err(FlexmapError::BlockFull { capacity: 5 })));
+        }
+
+        let vrange = values.get_range((0, 8));
+        let mut matches = Vec::new();
+        vrange.all_matches(|rpos, rval, strand| matches.push((rpos, rval, strand)));
+        assert_eq!(matches.len(), 5);
+        for (rpos, rval, strand) in matches {
+            assert_eq!(rval, 1);
+            assert_eq!(strand == Strand::Reverse, rpos % 2 == 1);
+        }
+
+        let mut best = Vec::new();
+        vrange.best_flex_match(&Kmer::<16>(21), |rpos, _, strand, dist| best.push((rpos, strand, dist)));
+        assert_eq!(best, vec![(3, Strand::Reverse, Some((FlankDist::default(), 1)))]);
+    }
+
+    #[test]
+    fn test_flex_matches_within() {
+        let mut values = FMValues::<16, 2>::new(8);
+        {
+            let mut vblock = values.get_range_mut((0, 8));
+            // Flanks 0b01 per mismatching base: 0, 1, 2, 1 and 3 mismatches against 0.
+            for (slot, flanks) in [0b0, 0b01, 0b0101, 0b010000, 0b010101].into_iter().enumerate() {
+                vblock.insert(slot, 1, slot as u64 * 10, Strand::Forward, flanks).unwrap();
+            }
+        }
+        let vrange = values.get_range((0, 8));
+        let hits = |max_dist, max_hits| -> Vec<(u64, Option<u32>)> {
+            vrange.flex_matches_within(&Kmer::<16>(0), max_dist, max_hits).iter().map(|hit| (hit.rpos, hit.dist.map(|dist| dist.total()))).collect()
+        };
+        assert_eq!(hits(0, 10), vec![(0, Some(0))]);
+        assert_eq!(hits(1, 10), vec![(0, Some(0)), (10, Some(1)), (30, Some(1))]);
+        assert_eq!(hits(2, 2), vec![(0, Some(0)), (10, Some(1))]);
+        assert_eq!(hits(16, 10).len(), 5);
+        assert_eq!(hits(16, 10).last(), Some(&(40, Some(3))));
+
+        // Only the right flank of a partial query is compared.
+        let right = vrange.flex_matches_masked(&Kmer::<16>(0b11 << 16), Flank::Right.mask::<16>(), 0, 10);
+        assert_eq!(right.iter().map(|hit| (hit.rpos, hit.dist)).collect::<Vec<_>>(), vec![(0, Some(FlankDist::default()))]);
+        let right = vrange.flex_matches_masked(&Kmer::<16>(0b11 << 16), Flank::Right.mask::<16>(), 16, 10);
+        assert_eq!(right.len(), 5);
+        assert!(right.iter().all(|hit| hit.dist.is_some_and(|dist| dist.left == 0)));
+
+        // Without a header every occurrence is a candidate.
+        let mut small = FMValues::<16, 2>::new(2);
+        small.get_range_mut((0, 2)).insert(0, 1, 7, Strand::Reverse, 0).unwrap();
+        small.get_range_mut((0, 2)).insert(1, 1, 8, Strand::Forward, 0).unwrap();
+        let hits = small.get_range((0, 2)).flex_matches_within(&Kmer::<16>(0), 0, 1);
+        assert_eq!(hits, vec![FlexMatch { rpos: 7, rval: 1, strand: Strand::Reverse, dist: None }]);
+    }
+
+    #[test]
+    fn test_split_flanks() {
+        assert_eq!(Flank::Right.mask::<16>(), 0x0000FFFF);
+        assert_eq!(Flank::Left.mask::<16>(), 0xFFFF0000);
+        assert_eq!(Flank::both::<8>(), 0xFFFF);
+
+        // Two mismatches on the left and none on the right, and the other way round.
+        let header = HeaderSeq(0b1111 << 16);
+        assert_eq!(header.dist(0), 2);
+        assert_eq!(header.flank_dist::<16>(0, Flank::both::<16>()), FlankDist { left: 2, right: 0 });
+        assert_eq!(header.flank_dist::<16>(0, Flank::Right.mask::<16>()), FlankDist { left: 0, right: 0 });
+        assert_eq!(HeaderSeq(0b10).flank_dist::<16>(0, Flank::both::<16>()), FlankDist { left: 0, right: 1 });
+
+        let mut values = FMValues::<16, 2>::new(8);
+        {
+            let mut vblock = values.get_range_mut((0, 8));
+            vblock.insert(0, 1, 0, Strand::Forward, 0b1111 << 16).unwrap();
+            vblock.insert(1, 1, 1, Strand::Forward, 0b01 << 16 | 0b01).unwrap();
+            vblock.insert(2, 1, 2, Strand::Forward, 0b0101).unwrap();
+            vblock.insert(3, 1, 3, Strand::Forward, 0b1111 << 20 | 0b1111).unwrap();
+            vblock.insert(4, 1, 4, Strand::Forward, 0b11 << 16 | 0b11).unwrap();
+        }
+        let vrange = values.get_range((0, 8));
+        let best = |flank: Option<Flank>| {
+            let mut best = Vec::new();
+            let collect = |rpos, _, _, dist: Option<(FlankDist, u32)>| best.push((rpos, dist.unwrap().0));
+            match flank {
+                Some(flank) => vrange.best_flank_match(&Kmer::<16>(0), flank, collect),
+                None => vrange.best_flex_match(&Kmer::<16>(0), collect),
+            }
+            best
+        };
+        // Over both flanks four occurrences have two mismatches ...
+        assert_eq!(best(None).iter().map(|(rpos, _)| *rpos).collect::<Vec<_>>(), vec![0, 1, 2, 4]);
+        // ... but only occurrence 0 matches the right flank exactly and only 2 the left one.
+        assert_eq!(best(Some(Flank::Right)), vec![(0, FlankDist { left: 0, right: 0 })]);
+        assert_eq!(best(Some(Flank::Left)), vec![(2, FlankDist { left: 0, right: 0 })]);
+    }
+
+    #[test]
+    fn test_shared_write_sort() {
+        let layout = ValueLayout::default();
+        let mut values = FMValues::<16, 2>::new(8);
+        let cell = |pos: u64| VCell(layout.pack(1, pos).unwrap());
+
+        // Occurrences arrive out of order, like from several threads.
+        {
+            let shared = values.shared();
+            let cursors = FillCursors::new(1);
+            for pos in [4u64, 2, 0, 3, 1] {
+                shared.write((0, 8), cursors.next(0), cell(pos), pos as u32 * 7).unwrap();
+            }
+            assert!(shared.write((0, 8), cursors.next(0), cell(5), 0).is_err());
+        }
+
+        let mut vblock = values.get_range_mut((0, 8));
+        vblock.sort();
+        let occurrences = vblock.occurrences();
+        let expected: Vec<(u64, u32)> = (0..5).map(|pos| (cell(pos).0, pos as u32 * 7)).collect();
+        assert_eq!(occurrences.iter().map(|(cell, flanks)| (cell.0, *flanks)).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_value_layout() {
+        let layout = ValueLayout::new(20, 40).unwrap();
+        assert_eq!(layout.unpack(layout.pack(5, 1 << 39).unwrap()), (5, 1 << 39));
+        assert!(layout.pack(1 << 20, 0).is_err());
+        assert!(layout.pack(0, 1 << 40).is_err());
+        assert!(ValueLayout::new(32, 32).is_err());
+        assert_eq!(ValueLayout::default(), ValueLayout::of::<28, 34>());
+
+        let mut values = FMValues::<16, 2>::with_layout(2, layout);
+        let mut vblock = values.get_range_mut((0, 2));
+        assert!(vblock.insert(0, 1 << 20, 0, Strand::Forward, 0).is_err());
+        vblock.insert(0, (1 << 20) - 1, 7, Strand::Reverse, 0).unwrap();
+
+        let mut matches = Vec::new();
+        values.get_range((0, 2)).all_matches(|rpos, rval, strand| matches.push((rpos, rval, strand)));
+        assert_eq!(matches, vec![(7, (1 << 20) - 1, Strand::Reverse), (0, 0, Strand::Forward)]);
     }
 }